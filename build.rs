@@ -0,0 +1,21 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Bakes the commit and build time into the binary for GET /version
+fn main() {
+    // Builds without a .git directory (Docker and the like) can pass the commit in instead
+    println!("cargo:rerun-if-env-changed=IPA_GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    let git_sha = std::env::var("IPA_GIT_SHA").ok()
+        .or_else(|| {
+            let output = Command::new("git").args(["rev-parse", "HEAD"]).output().ok()?;
+            output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    let built_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+
+    println!("cargo:rustc-env=IPA_GIT_SHA={git_sha}");
+    println!("cargo:rustc-env=IPA_BUILD_TIMESTAMP={built_at}");
+}
\ No newline at end of file
@@ -0,0 +1,50 @@
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::State;
+
+use crate::auth::{ApiKey, ApiKeys};
+use crate::cors::AllowedOrigins;
+use crate::error::ApiError;
+use crate::ip_filter::{IpDenylist, NotDenied};
+use crate::language_map::{LanguageMap, SharedLanguageMap};
+use crate::rate_limit::RateLimits;
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReloadSummary {
+    changed: Vec<&'static str>,
+}
+
+// Re-reads the language map (default engines, IPA lengths, carriers and the default language with it),
+// the rate limit exemptions, the IP denylist, the API keys and the CORS origins. A process's environment
+// can't change under it, so this picks up edits to the files those variables point at.
+#[post("/admin/reload")]
+pub fn reload(languages: &State<SharedLanguageMap>, rate_limits: &State<RateLimits>, ip_denylist: &State<IpDenylist>, api_keys: &State<ApiKeys>, allowed_origins: &State<AllowedOrigins>, _not_denied: NotDenied, api_key: ApiKey) -> Result<Json<ReloadSummary>, ApiError> {
+    if api_key.0.is_none() {
+        return Err(ApiError::new(Status::Forbidden, "api_keys_disabled", "Reloading is only available when IPA_API_KEYS is set".to_string()));
+    }
+
+    // Nothing gets swapped if the language map is unusable, same as it wouldn't start
+    let language_map = LanguageMap::from_env().map_err(|err| ApiError::new(Status::InternalServerError, "invalid_config", err))?;
+
+    let mut changed = Vec::new();
+    if languages.replace(language_map) {
+        changed.push("languages");
+    }
+    if rate_limits.reload() {
+        changed.push("rate_limit_exempt");
+    }
+    if ip_denylist.reload() {
+        changed.push("ip_denylist");
+    }
+    if api_keys.reload() {
+        changed.push("api_keys");
+    }
+    if allowed_origins.reload() {
+        changed.push("cors_origins");
+    }
+    info!("Reloaded configuration, changed: {changed:?}");
+
+    Ok(Json(ReloadSummary { changed }))
+}
\ No newline at end of file
@@ -0,0 +1,79 @@
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::Request;
+
+use crate::config;
+use crate::error::ApiError;
+
+const API_KEY_HEADER: &str = "X-API-Key";
+
+// No keys configured leaves the server open, like it was before keys existed
+pub struct ApiKeys(RwLock<HashSet<String>>);
+
+fn keys_from_env() -> HashSet<String> {
+    config::env_list_and_file("IPA_API_KEYS", "IPA_API_KEYS_FILE").into_iter().collect()
+}
+
+impl ApiKeys {
+    pub fn from_env() -> Self {
+        ApiKeys(RwLock::new(keys_from_env()))
+    }
+
+    // Returns whether it's any different
+    pub fn reload(&self) -> bool {
+        let keys = keys_from_env();
+        let mut current = self.0.write().unwrap();
+        let changed = *current != keys;
+        *current = keys;
+        changed
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.0.read().unwrap().is_empty()
+    }
+
+    fn contains(&self, api_key: &str) -> bool {
+        self.0.read().unwrap().contains(api_key)
+    }
+}
+
+// The key a request got in with, None when auth is off
+pub struct ApiKey(pub Option<String>);
+
+// The catcher only gets the status, so the guard leaves the reason here
+struct AuthFailure(&'static str, &'static str);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKey {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let api_keys = match request.rocket().state::<ApiKeys>() {
+            Some(api_keys) if api_keys.is_enabled() => api_keys,
+            _ => return request::Outcome::Success(ApiKey(None)),
+        };
+
+        match request.headers().get_one(API_KEY_HEADER) {
+            Some(api_key) if api_keys.contains(api_key) => request::Outcome::Success(ApiKey(Some(api_key.to_string()))),
+            Some(_) => {
+                request.local_cache(|| Some(AuthFailure("invalid_api_key", "The X-API-Key header isn't a valid API key")));
+                request::Outcome::Error((Status::Unauthorized, ()))
+            }
+            None => {
+                request.local_cache(|| Some(AuthFailure("missing_api_key", "An API key is required in the X-API-Key header")));
+                request::Outcome::Error((Status::Unauthorized, ()))
+            }
+        }
+    }
+}
+
+#[catch(401)]
+pub fn unauthorized_catcher(request: &Request) -> ApiError {
+    match request.local_cache(|| None::<AuthFailure>) {
+        Some(AuthFailure(code, message)) => ApiError::new(Status::Unauthorized, code, message.to_string()),
+        None => ApiError::new(Status::Unauthorized, "unauthorized", "The request isn't authorized".to_string()),
+    }
+}
\ No newline at end of file
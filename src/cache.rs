@@ -0,0 +1,98 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+// Least recently used entries are evicted first. Recency is a ticking counter so
+// finding the oldest entry is just the first key of the BTreeMap.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    recency: BTreeMap<u64, K>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.recency.remove(last_used);
+        self.recency.insert(self.tick, key.clone());
+        *last_used = self.tick;
+
+        Some(value.clone())
+    }
+
+    // Returns how many entries were evicted to make room
+    pub fn put(&mut self, key: K, value: V) -> usize {
+        if self.capacity == 0 {
+            return 0;
+        }
+
+        self.tick += 1;
+        if let Some((_, last_used)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.tick, key);
+
+        let mut evicted = 0;
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else { break };
+            self.entries.remove(&oldest);
+            evicted += 1;
+        }
+
+        evicted
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        assert_eq!(cache.put("a", 1), 0);
+        assert_eq!(cache.put("b", 2), 0);
+        // Reading a makes b the oldest
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.put("c", 3), 1);
+
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"c"), Some(3));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn overwriting_is_not_an_eviction() {
+        let mut cache = LruCache::new(2);
+        cache.put("a", 1);
+        cache.put("b", 2);
+        assert_eq!(cache.put("a", 10), 0);
+        assert_eq!(cache.get(&"a"), Some(10));
+        // a was bumped by the overwrite, so b goes
+        assert_eq!(cache.put("c", 3), 1);
+        assert_eq!(cache.get(&"b"), None);
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut cache = LruCache::new(0);
+        assert_eq!(cache.put("a", 1), 0);
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.len(), 0);
+    }
+}
\ No newline at end of file
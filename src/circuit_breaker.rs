@@ -0,0 +1,190 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::http::Status;
+
+use crate::config;
+use crate::error::ApiError;
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_COOLDOWN_SECS: u64 = 30;
+// Failures further apart than this don't add up to an outage
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+enum State {
+    Closed { failures: u32, first_failure: Instant },
+    // Turning everything away until then
+    Open { until: Instant },
+    // One request has been let through to see whether Polly is back
+    HalfOpen { since: Instant },
+}
+
+// Stops sending requests to Polly for a while once it keeps failing, rather than have every
+// request wait out the timeout. Only 5xx failures count, a 400 still means Polly answered, and so
+// does being throttled. Timing out does count, that's the wait this is here to save.
+pub struct CircuitBreaker {
+    // 0 means never trip
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn from_env() -> Self {
+        Self::new(
+            config::env_or("IPA_CIRCUIT_BREAKER_FAILURES", DEFAULT_FAILURE_THRESHOLD),
+            Duration::from_secs(config::env_or("IPA_CIRCUIT_BREAKER_COOLDOWN_SECS", DEFAULT_COOLDOWN_SECS)),
+        )
+    }
+
+    fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker { failure_threshold, cooldown, state: Mutex::new(State::Closed { failures: 0, first_failure: Instant::now() }) }
+    }
+
+    // Call before going to Polly
+    pub fn check(&self) -> Result<(), ApiError> {
+        let mut state = self.state.lock().unwrap();
+        let retry_after = match *state {
+            State::Closed { .. } => return Ok(()),
+            State::Open { until } if Instant::now() >= until => {
+                info!("Letting a request through to see if Polly has recovered");
+                *state = State::HalfOpen { since: Instant::now() };
+                return Ok(());
+            }
+            State::Open { until } => until.saturating_duration_since(Instant::now()).as_secs().max(1),
+            // The trial request might never have finished, so don't wait on it forever
+            State::HalfOpen { since } if since.elapsed() >= self.cooldown => {
+                *state = State::HalfOpen { since: Instant::now() };
+                return Ok(());
+            }
+            State::HalfOpen { .. } => 1,
+        };
+
+        Err(ApiError::new(Status::ServiceUnavailable, "polly_unavailable", "Polly is failing at the moment, try again shortly".to_string()).with_retry_after(retry_after))
+    }
+
+    // Call with however the Polly call went
+    pub fn record<T>(&self, result: &Result<T, ApiError>) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let failed = result.as_ref().is_err_and(|error| error.status().code >= 500 && error.code() != "polly_throttled");
+        if !failed {
+            if matches!(*state, State::HalfOpen { .. }) {
+                info!("Polly has recovered, sending requests to it again");
+            }
+            *state = State::Closed { failures: 0, first_failure: Instant::now() };
+            return;
+        }
+
+        let now = Instant::now();
+        match *state {
+            State::Closed { failures, first_failure } => {
+                let failures = if now.duration_since(first_failure) > FAILURE_WINDOW { 1 } else { failures + 1 };
+                if failures >= self.failure_threshold {
+                    warn!("Polly failed {failures} times within a minute, turning requests away for {}s", self.cooldown.as_secs());
+                    *state = State::Open { until: now + self.cooldown };
+                } else {
+                    let first_failure = if failures == 1 { now } else { first_failure };
+                    *state = State::Closed { failures, first_failure };
+                }
+            }
+            State::HalfOpen { .. } => {
+                warn!("Polly still failing, turning requests away for another {}s", self.cooldown.as_secs());
+                *state = State::Open { until: now + self.cooldown };
+            }
+            // Something already in flight when it tripped
+            State::Open { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn failure() -> Result<(), ApiError> {
+        Err(ApiError::new(Status::InternalServerError, "synthesis_failed", "Polly fell over".to_string()))
+    }
+
+    fn tripped(breaker: &CircuitBreaker) -> bool {
+        breaker.check().is_err()
+    }
+
+    #[test]
+    fn opens_after_enough_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+        breaker.record(&failure());
+        breaker.record(&failure());
+        assert!(!tripped(&breaker));
+        breaker.record(&failure());
+
+        let error = breaker.check().unwrap_err();
+        assert_eq!(error.status(), Status::ServiceUnavailable);
+        assert_eq!(error.code(), "polly_unavailable");
+        assert!(matches!(error.retry_after(), Some(29..=30)), "{:?}", error.retry_after());
+    }
+
+    #[test]
+    fn success_starts_the_count_again() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+        breaker.record(&failure());
+        breaker.record(&failure());
+        breaker.record(&Ok(()));
+        breaker.record(&failure());
+        breaker.record(&failure());
+        assert!(!tripped(&breaker));
+    }
+
+    #[test]
+    fn only_outages_count() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        breaker.record::<()>(&Err(ApiError::bad_request("invalid_ipa", "Not IPA".to_string())));
+        breaker.record::<()>(&Err(ApiError::new(Status::ServiceUnavailable, "polly_throttled", "Slow down".to_string())));
+        assert!(!tripped(&breaker));
+        breaker.record::<()>(&Err(ApiError::new(Status::GatewayTimeout, "synthesis_timeout", "Too slow".to_string())));
+        assert!(tripped(&breaker));
+    }
+
+    #[test]
+    fn zero_threshold_never_trips() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(30));
+        for _ in 0..10 {
+            breaker.record(&failure());
+        }
+        assert!(!tripped(&breaker));
+    }
+
+    #[test]
+    fn half_open_after_the_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record(&failure());
+        assert!(tripped(&breaker));
+        thread::sleep(Duration::from_millis(60));
+
+        // One trial request, everyone else waits on how it goes
+        assert!(!tripped(&breaker));
+        assert_eq!(breaker.check().unwrap_err().retry_after(), Some(1));
+
+        breaker.record(&Ok(()));
+        assert!(!tripped(&breaker));
+        assert!(!tripped(&breaker));
+    }
+
+    #[test]
+    fn failing_while_half_open_opens_again() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record(&failure());
+        thread::sleep(Duration::from_millis(60));
+        assert!(!tripped(&breaker));
+
+        breaker.record(&failure());
+        assert!(tripped(&breaker));
+        thread::sleep(Duration::from_millis(60));
+        assert!(!tripped(&breaker));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,146 @@
+use std::io::{Cursor, Write};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Request, Response};
+
+// Smaller than this and the gzip header eats most of the saving
+const MIN_COMPRESSED_BYTES: usize = 1024;
+
+// Gzips JSON and text for clients that accept it. Audio is left alone, it's already compressed
+// (or PCM, which nobody asks for to save bandwidth).
+pub struct Gzip;
+
+fn accepts_gzip(request: &Request<'_>) -> bool {
+    request.headers().get("Accept-Encoding")
+        .flat_map(|header| header.split(','))
+        .any(|encoding| {
+            let mut parts = encoding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            // gzip;q=0 means anything but gzip
+            let refused = parts.any(|parameter| parameter.strip_prefix("q=").and_then(|quality| quality.parse::<f32>().ok()) == Some(0.0));
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+#[rocket::async_trait]
+impl Fairing for Gzip {
+    fn info(&self) -> Info {
+        Info {
+            name: "Gzip JSON and text responses",
+            kind: Kind::Response
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let compressible = response.content_type().is_some_and(|content_type| content_type.is_json() || content_type.top() == "text");
+        if !compressible || response.headers().contains("Content-Encoding") {
+            return;
+        }
+        // Whether it ends up compressed or not, caches have to keep the two apart
+        response.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+        if !accepts_gzip(request) {
+            return;
+        }
+
+        let body = match response.body_mut().to_bytes().await {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("Failed to read a response body to compress: {err}");
+                return;
+            }
+        };
+        if body.len() < MIN_COMPRESSED_BYTES {
+            response.set_sized_body(body.len(), Cursor::new(body));
+            return;
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        match encoder.write_all(&body).and_then(|_| encoder.finish()) {
+            Ok(compressed) => {
+                response.set_header(Header::new("Content-Encoding", "gzip"));
+                response.set_sized_body(compressed.len(), Cursor::new(compressed));
+            }
+            Err(err) => {
+                warn!("Failed to gzip a response, sending it as is: {err}");
+                response.set_sized_body(body.len(), Cursor::new(body));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use rocket::http::ContentType;
+    use rocket::local::blocking::Client;
+
+    use super::*;
+
+    fn large_json() -> String {
+        format!("[{}]", vec!["\"həˈloʊ\""; 500].join(","))
+    }
+
+    #[get("/json")]
+    fn json() -> (ContentType, String) {
+        (ContentType::JSON, large_json())
+    }
+
+    #[get("/small")]
+    fn small() -> (ContentType, &'static str) {
+        (ContentType::JSON, "[]")
+    }
+
+    #[get("/audio")]
+    fn audio() -> (ContentType, Vec<u8>) {
+        (ContentType::new("audio", "mpeg"), vec![0; 4 * MIN_COMPRESSED_BYTES])
+    }
+
+    fn client() -> Client {
+        Client::tracked(rocket::build().mount("/", routes![json, small, audio]).attach(Gzip)).unwrap()
+    }
+
+    #[test]
+    fn gzips_large_json_when_accepted() {
+        let client = client();
+        let response = client.get("/json").header(Header::new("Accept-Encoding", "br, gzip")).dispatch();
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+        assert!(response.headers().get("Vary").any(|vary| vary == "Accept-Encoding"));
+
+        let compressed = response.into_bytes().unwrap();
+        assert!(compressed.len() < large_json().len());
+        let mut decompressed = String::new();
+        GzDecoder::new(compressed.as_slice()).read_to_string(&mut decompressed).unwrap();
+        assert_eq!(decompressed, large_json());
+    }
+
+    #[test]
+    fn sends_json_as_is_unless_gzip_is_accepted() {
+        let client = client();
+        for accept_encoding in [None, Some("identity"), Some("br"), Some("gzip;q=0")] {
+            let mut request = client.get("/json");
+            if let Some(accept_encoding) = accept_encoding {
+                request = request.header(Header::new("Accept-Encoding", accept_encoding));
+            }
+            let response = request.dispatch();
+            assert_eq!(response.headers().get_one("Content-Encoding"), None, "{accept_encoding:?}");
+            assert!(response.headers().get("Vary").any(|vary| vary == "Accept-Encoding"), "{accept_encoding:?}");
+            assert_eq!(response.into_string(), Some(large_json()), "{accept_encoding:?}");
+        }
+    }
+
+    #[test]
+    fn leaves_small_bodies_and_audio_alone() {
+        let client = client();
+        for path in ["/small", "/audio"] {
+            let response = client.get(path).header(Header::new("Accept-Encoding", "gzip")).dispatch();
+            assert_eq!(response.headers().get_one("Content-Encoding"), None, "{path}");
+        }
+        let response = client.get("/audio").header(Header::new("Accept-Encoding", "gzip")).dispatch();
+        assert_eq!(response.into_bytes().map(|audio| audio.len()), Some(4 * MIN_COMPRESSED_BYTES));
+    }
+}
\ No newline at end of file
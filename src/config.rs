@@ -0,0 +1,145 @@
+use std::env;
+use std::fs;
+use std::str::FromStr;
+
+use crate::voice_selection::Strategy;
+use crate::{FORMAT_NAMES, FORMAT_TO_OUTPUT};
+
+// Falls back to the default when unset or unparseable, logging the latter so typos don't go unnoticed
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            warn!("Ignoring invalid value {value:?} for {name}");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+// None when unset, for settings that should only override someone else's default when they're actually given
+pub fn env_opt<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    let parsed = value.trim().parse().ok();
+    if parsed.is_none() {
+        warn!("Ignoring invalid value {value:?} for {name}");
+    }
+    parsed
+}
+
+// Comma-separated, ignoring whitespace and empty entries
+pub fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .map(|value| value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+// A comma-separated variable plus a file of one entry per line, # starting a comment. Unlike the
+// variable, the file can change under a running server, so this is what POST /admin/reload re-reads.
+pub fn env_list_and_file(list_variable: &str, file_variable: &str) -> Vec<String> {
+    let mut entries = env_list(list_variable);
+    let path = env_or(file_variable, String::new());
+    if !path.is_empty() {
+        match fs::read_to_string(&path) {
+            Ok(contents) => entries.extend(contents.lines()
+                .map(|line| line.split('#').next().unwrap_or_default().trim())
+                .filter(|line| !line.is_empty())
+                .map(String::from)),
+            Err(err) => error!("Failed to read {path} from {file_variable}: {err}"),
+        }
+    }
+    entries
+}
+
+// Tests run in parallel and the environment is shared by all of them, so any test touching it holds this
+#[cfg(test)]
+pub fn lock_env() -> std::sync::MutexGuard<'static, ()> {
+    static ENV: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// A day. The audio for a request never changes, but we might want to change how requests are synthesized.
+const DEFAULT_CACHE_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+// Runtime settings the handlers need, managed by Rocket
+pub struct Settings {
+    pub validate_characters: bool,
+    // Sent in Cache-Control on audio, 0 leaves it out
+    pub cache_max_age_secs: u64,
+    // For requests without a strategy of their own
+    pub voice_strategy: Strategy,
+    // The HTML page on / and /playground
+    pub playground: bool,
+    // In the order given, so a list without ogg can still pick a default
+    allowed_formats: Vec<&'static str>,
+}
+
+impl Settings {
+    pub fn from_env() -> Self {
+        Settings {
+            validate_characters: env_or("IPA_VALIDATE_CHARACTERS", false),
+            cache_max_age_secs: env_or("IPA_CACHE_MAX_AGE_SECS", DEFAULT_CACHE_MAX_AGE_SECS),
+            voice_strategy: env_or("IPA_VOICE_STRATEGY", Strategy::Random),
+            playground: env_or("IPA_PLAYGROUND", true),
+            allowed_formats: allowed_formats(env_list("IPA_ALLOWED_FORMATS")),
+        }
+    }
+
+    // As if IPA_ALLOWED_FORMATS were set to format_names, without touching the environment other tests read
+    #[cfg(test)]
+    pub fn with_allowed_formats(format_names: &[&str]) -> Self {
+        Settings { allowed_formats: allowed_formats(format_names.iter().map(|format_name| format_name.to_string()).collect()), ..Self::from_env() }
+    }
+
+    pub fn allows_format(&self, format_name: &str) -> bool {
+        self.allowed_formats.contains(&format_name)
+    }
+
+    pub fn allowed_formats(&self) -> &[&'static str] {
+        &self.allowed_formats
+    }
+
+    // Ogg since that's all we used to return, unless it's been turned off
+    pub fn default_format(&self) -> &'static str {
+        if self.allows_format("ogg") { "ogg" } else { self.allowed_formats[0] }
+    }
+}
+
+// Formats we can't produce are dropped, and an empty list allows all of them
+fn allowed_formats(format_names: Vec<String>) -> Vec<&'static str> {
+    let mut allowed_formats = Vec::new();
+    for format_name in format_names {
+        match FORMAT_TO_OUTPUT.get_key_value(format_name.as_str()) {
+            Some((format_name, _)) => allowed_formats.push(*format_name),
+            None => warn!("Ignoring {format_name} in IPA_ALLOWED_FORMATS, it isn't a format we can produce"),
+        }
+    }
+
+    if allowed_formats.is_empty() {
+        allowed_formats = FORMAT_NAMES.to_vec();
+    }
+    allowed_formats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(format_names: &[&str]) -> Vec<&'static str> {
+        allowed_formats(format_names.iter().map(|format_name| format_name.to_string()).collect())
+    }
+
+    #[test]
+    fn allowed_formats_keep_their_order() {
+        assert_eq!(allowed(&["pcm", "mp3"]), ["pcm", "mp3"]);
+        assert_eq!(allowed(&["wav", "ogg", "flac"]), ["ogg"]);
+        assert_eq!(allowed(&[]), FORMAT_NAMES);
+        assert_eq!(allowed(&["flac"]), FORMAT_NAMES);
+    }
+
+    #[test]
+    fn default_format_is_ogg_unless_it_is_turned_off() {
+        assert_eq!(Settings::with_allowed_formats(&[]).default_format(), "ogg");
+        assert_eq!(Settings::with_allowed_formats(&["mp3", "ogg"]).default_format(), "ogg");
+        assert_eq!(Settings::with_allowed_formats(&["mp3", "pcm"]).default_format(), "mp3");
+    }
+}
\ No newline at end of file
@@ -1,8 +1,58 @@
-use rocket::http::Header;
+use std::sync::{Arc, RwLock};
+
+use rocket::http::{Header, Method};
 use rocket::{Request, Response};
 use rocket::fairing::{Fairing, Info, Kind};
 
-pub struct CORS;
+use crate::config;
+
+const DEFAULT_ALLOWED_HEADERS: &str = "Content-Type, Charset, Accept, Authorization, X-API-Key, Range, If-Range";
+// Anything not on the CORS safelist is hidden from page scripts unless listed here.
+// The rate limit ones are what rate_limit::RateLimitHeaders sets.
+const EXPOSED_HEADERS: [&str; 13] = [
+    "X-IPA-Voice", "X-IPA-Voice-Fallback", "X-IPA-Language-Code", "X-IPA-Warnings", "X-Request-Id", "X-Audio-Duration-Ms", "ETag", "Content-Disposition", "Content-Range", "Accept-Ranges",
+    "X-RateLimit-Limit", "X-RateLimit-Remaining", "Retry-After",
+];
+// Browsers cap this themselves (Chrome at two hours), so there's no point going higher
+const PREFLIGHT_MAX_AGE_SECS: &str = "7200";
+
+// Shared between the fairing and managed state, so POST /admin/reload can swap the list. Empty means any origin.
+#[derive(Clone)]
+pub struct AllowedOrigins(Arc<RwLock<Vec<String>>>);
+
+fn origins_from_env() -> Vec<String> {
+    config::env_list_and_file("IPA_CORS_ORIGINS", "IPA_CORS_ORIGINS_FILE")
+}
+
+impl AllowedOrigins {
+    pub fn from_env() -> Self {
+        AllowedOrigins(Arc::new(RwLock::new(origins_from_env())))
+    }
+
+    // Returns whether it's any different
+    pub fn reload(&self) -> bool {
+        let origins = origins_from_env();
+        let mut current = self.0.write().unwrap();
+        let changed = *current != origins;
+        *current = origins;
+        changed
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct CORS {
+    allowed: AllowedOrigins,
+    exposed_headers: String,
+}
+
+impl CORS {
+    pub fn new(allowed: AllowedOrigins) -> Self {
+        let mut exposed_headers: Vec<String> = EXPOSED_HEADERS.iter().map(|header| header.to_string()).collect();
+        exposed_headers.extend(config::env_list("IPA_CORS_EXPOSE_HEADERS"));
+
+        CORS { allowed, exposed_headers: exposed_headers.join(", ") }
+    }
+}
 
 #[rocket::async_trait]
 impl Fairing for CORS {
@@ -13,9 +63,81 @@ impl Fairing for CORS {
         }
     }
 
-    async fn on_response<'r>(&self, _request: &'r Request<'_>, response: &mut Response<'r>) {
-        response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let allowed = self.allowed.0.read().unwrap().clone();
+        if allowed.is_empty() {
+            response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+        } else {
+            // Only echo back origins on the list, anything else gets no CORS headers and the browser blocks it
+            match request.headers().get_one("Origin") {
+                Some(origin) if allowed.iter().any(|allowed| allowed == origin) => {
+                    response.set_header(Header::new("Access-Control-Allow-Origin", origin.to_string()));
+                    // Alongside whatever else the response varies on
+                    response.adjoin_header(Header::new("Vary", "Origin"));
+                }
+                _ => return,
+            }
+        }
+
         response.set_header(Header::new("Access-Control-Allow-Methods", "GET, POST, OPTIONS"));
-        response.set_header(Header::new("Access-Control-Allow-Headers", "Content-Type, Charset, Accept"));
+        response.set_header(Header::new("Access-Control-Expose-Headers", self.exposed_headers.clone()));
+
+        // Echoing a preflight's requested headers is no looser than listing them, the origin check is what matters
+        match request.headers().get_one("Access-Control-Request-Headers") {
+            Some(requested_headers) if request.method() == Method::Options => {
+                response.set_header(Header::new("Access-Control-Allow-Headers", requested_headers.to_string()));
+            }
+            _ => {
+                response.set_header(Header::new("Access-Control-Allow-Headers", DEFAULT_ALLOWED_HEADERS));
+            }
+        }
+
+        if request.method() == Method::Options {
+            response.set_header(Header::new("Access-Control-Max-Age", PREFLIGHT_MAX_AGE_SECS));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::blocking::Client;
+
+    use super::*;
+
+    #[get("/")]
+    fn index() -> &'static str {
+        "hello"
+    }
+
+    fn client(origins: &[&str]) -> Client {
+        let allowed = AllowedOrigins(Arc::new(RwLock::new(origins.iter().map(|origin| origin.to_string()).collect())));
+        Client::tracked(rocket::build().mount("/", routes![index]).attach(CORS::new(allowed))).unwrap()
+    }
+
+    #[test]
+    fn echoes_an_allowed_origin() {
+        let client = client(&["https://example.com", "https://other.example"]);
+        let response = client.get("/").header(Header::new("Origin", "https://other.example")).dispatch();
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), Some("https://other.example"));
+        assert!(response.headers().get("Vary").any(|vary| vary == "Origin"));
+        assert!(response.headers().get_one("Access-Control-Expose-Headers").is_some_and(|exposed| exposed.contains("X-IPA-Voice")));
+    }
+
+    #[test]
+    fn leaves_out_other_origins() {
+        let client = client(&["https://example.com"]);
+        for origin in ["https://evil.example", "https://example.com.evil.example", "http://example.com"] {
+            let response = client.get("/").header(Header::new("Origin", origin)).dispatch();
+            assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None, "{origin}");
+            assert_eq!(response.headers().get_one("Access-Control-Allow-Methods"), None, "{origin}");
+        }
+        assert_eq!(client.get("/").dispatch().headers().get_one("Access-Control-Allow-Origin"), None);
+    }
+
+    #[test]
+    fn empty_list_allows_any_origin() {
+        let client = client(&[]);
+        let response = client.get("/").header(Header::new("Origin", "https://anywhere.example")).dispatch();
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), Some("*"));
     }
 }
\ No newline at end of file
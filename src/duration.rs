@@ -0,0 +1,186 @@
+use aws_sdk_polly::model::OutputFormat;
+
+// What Polly uses for PCM when no sample rate is given
+const DEFAULT_PCM_SAMPLE_RATE: u64 = 16000;
+
+// Worked out from the audio itself, so it costs nothing extra from Polly.
+// None if the audio isn't laid out the way Polly produces it.
+pub fn duration_ms(audio: &[u8], output_format: &OutputFormat, sample_rate: Option<&str>) -> Option<u64> {
+    match output_format {
+        // Signed 16-bit mono, so it's just the length
+        OutputFormat::Pcm => {
+            let sample_rate = sample_rate.map_or(Some(DEFAULT_PCM_SAMPLE_RATE), |sample_rate| sample_rate.parse().ok())?;
+            Some(audio.len() as u64 / 2 * 1000 / sample_rate)
+        }
+        OutputFormat::OggVorbis => ogg_duration_ms(audio),
+        OutputFormat::Mp3 => mp3_duration_ms(audio),
+        _ => None,
+    }
+}
+
+// The last page's granule position is the total number of samples, and the sample rate is in the
+// identification header that starts the first page
+fn ogg_duration_ms(audio: &[u8]) -> Option<u64> {
+    let segment_count = *audio.get(26)? as usize;
+    let identification = audio.get(27 + segment_count..)?;
+    if !identification.starts_with(b"\x01vorbis") {
+        return None;
+    }
+    let sample_rate = u32::from_le_bytes(identification.get(12..16)?.try_into().ok()?) as u64;
+    if sample_rate == 0 {
+        return None;
+    }
+
+    let last_page = audio.windows(4).rposition(|window| window == b"OggS")?;
+    let granule_position = u64::from_le_bytes(audio.get(last_page + 6..last_page + 14)?.try_into().ok()?);
+
+    Some(granule_position * 1000 / sample_rate)
+}
+
+// Layer III bitrates in kbps by bitrate index, MPEG-1 then MPEG-2 and 2.5
+const MPEG1_BITRATES: [u64; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+const MPEG2_BITRATES: [u64; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+// Adds up every frame rather than trusting the first, in case the bitrate varies
+fn mp3_duration_ms(audio: &[u8]) -> Option<u64> {
+    let mut offset = 0;
+    // Skip an ID3v2 tag, its size is stored 7 bits to a byte
+    if audio.starts_with(b"ID3") {
+        let size = audio.get(6..10)?.iter().fold(0, |size, byte| (size << 7) | (*byte as usize & 0x7F));
+        offset = 10 + size;
+    }
+
+    let (mut samples, mut sample_rate) = (0, 0);
+    while let Some(header) = audio.get(offset..offset + 4) {
+        if header[0] != 0xFF || header[1] & 0xE0 != 0xE0 || (header[1] >> 1) & 0x03 != 0x01 {
+            break;
+        }
+
+        let version = (header[1] >> 3) & 0x03;
+        let (bitrates, sample_rates, samples_per_frame) = match version {
+            0b11 => (&MPEG1_BITRATES, [44100, 48000, 32000], 1152),
+            0b10 => (&MPEG2_BITRATES, [22050, 24000, 16000], 576),
+            0b00 => (&MPEG2_BITRATES, [11025, 12000, 8000], 576),
+            _ => break,
+        };
+        let bitrate = *bitrates.get((header[2] >> 4) as usize)?;
+        let frame_sample_rate = *sample_rates.get(((header[2] >> 2) & 0x03) as usize)?;
+        if bitrate == 0 {
+            break;
+        }
+        sample_rate = frame_sample_rate;
+
+        let padding = ((header[2] >> 1) & 0x01) as u64;
+        let frame_length = samples_per_frame / 8 * bitrate * 1000 / sample_rate + padding;
+        samples += samples_per_frame;
+        offset += frame_length as usize;
+    }
+
+    if sample_rate == 0 {
+        return None;
+    }
+
+    Some(samples * 1000 / sample_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MPEG-1 Layer III at 128kbps and 44.1kHz, 417 bytes a frame or 418 with padding
+    const MPEG1_HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x00];
+    const MPEG1_PADDED_HEADER: [u8; 4] = [0xFF, 0xFB, 0x92, 0x00];
+    // MPEG-2 Layer III at 32kbps and 22.05kHz, 104 bytes a frame
+    const MPEG2_HEADER: [u8; 4] = [0xFF, 0xF3, 0x40, 0x00];
+
+    fn mp3_frame(header: [u8; 4], length: usize) -> Vec<u8> {
+        let mut frame = header.to_vec();
+        frame.resize(length, 0);
+        frame
+    }
+
+    fn ogg_page(granule_position: u64, packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\x00\x00".to_vec();
+        page.extend_from_slice(&granule_position.to_le_bytes());
+        // Serial number, sequence number and checksum, none of which get looked at
+        page.extend_from_slice(&[0; 12]);
+        page.push(1);
+        page.push(packet.len() as u8);
+        page.extend_from_slice(packet);
+        page
+    }
+
+    fn vorbis_identification(sample_rate: u32) -> Vec<u8> {
+        let mut packet = b"\x01vorbis\x00\x00\x00\x00\x01".to_vec();
+        packet.extend_from_slice(&sample_rate.to_le_bytes());
+        packet.resize(30, 0);
+        packet
+    }
+
+    #[test]
+    fn pcm_is_two_bytes_a_sample() {
+        let second = vec![0; 32000];
+        assert_eq!(duration_ms(&second, &OutputFormat::Pcm, None), Some(1000));
+        assert_eq!(duration_ms(&second, &OutputFormat::Pcm, Some("8000")), Some(2000));
+        assert_eq!(duration_ms(&second[..3], &OutputFormat::Pcm, Some("8000")), Some(0));
+        assert_eq!(duration_ms(&second, &OutputFormat::Pcm, Some("fast")), None);
+        assert_eq!(duration_ms(&second, &OutputFormat::Json, None), None);
+    }
+
+    #[test]
+    fn mp3_adds_up_every_frame() {
+        let audio: Vec<u8> = (0..10).flat_map(|_| mp3_frame(MPEG1_HEADER, 417)).collect();
+        // 10 frames of 1152 samples
+        assert_eq!(duration_ms(&audio, &OutputFormat::Mp3, None), Some(11520 * 1000 / 44100));
+
+        let mut padded = mp3_frame(MPEG1_PADDED_HEADER, 418);
+        padded.extend(mp3_frame(MPEG1_HEADER, 417));
+        assert_eq!(duration_ms(&padded, &OutputFormat::Mp3, None), Some(2304 * 1000 / 44100));
+
+        let audio: Vec<u8> = (0..5).flat_map(|_| mp3_frame(MPEG2_HEADER, 104)).collect();
+        assert_eq!(duration_ms(&audio, &OutputFormat::Mp3, None), Some(2880 * 1000 / 22050));
+    }
+
+    #[test]
+    fn mp3_skips_the_id3_tag() {
+        let mut audio = b"ID3\x03\x00\x00\x00\x00\x01\x00".to_vec();
+        audio.resize(10 + 128, 0);
+        audio.extend(mp3_frame(MPEG1_HEADER, 417));
+        assert_eq!(duration_ms(&audio, &OutputFormat::Mp3, None), Some(1152 * 1000 / 44100));
+    }
+
+    #[test]
+    fn mp3_stops_at_anything_that_isnt_a_frame() {
+        let mut audio = mp3_frame(MPEG1_HEADER, 417);
+        audio.extend_from_slice(b"TAGtrailing junk that could look like anything");
+        assert_eq!(duration_ms(&audio, &OutputFormat::Mp3, None), Some(1152 * 1000 / 44100));
+
+        assert_eq!(duration_ms(b"", &OutputFormat::Mp3, None), None);
+        assert_eq!(duration_ms(&MPEG1_HEADER[..3], &OutputFormat::Mp3, None), None);
+        assert_eq!(duration_ms(b"definitely not an mp3", &OutputFormat::Mp3, None), None);
+        // Cut off in the middle of the tag
+        assert_eq!(duration_ms(b"ID3\x03\x00", &OutputFormat::Mp3, None), None);
+        // Free format has no bitrate to go on
+        assert_eq!(duration_ms(&mp3_frame([0xFF, 0xFB, 0x00, 0x00], 417), &OutputFormat::Mp3, None), None);
+    }
+
+    #[test]
+    fn ogg_uses_the_last_granule_position() {
+        let mut audio = ogg_page(0, &vorbis_identification(24000));
+        audio.extend(ogg_page(12000, &[0; 50]));
+        audio.extend(ogg_page(36000, &[0; 50]));
+        assert_eq!(duration_ms(&audio, &OutputFormat::OggVorbis, None), Some(1500));
+    }
+
+    #[test]
+    fn ogg_without_a_vorbis_header_is_unknown() {
+        assert_eq!(duration_ms(b"", &OutputFormat::OggVorbis, None), None);
+        assert_eq!(duration_ms(&ogg_page(0, &vorbis_identification(24000))[..20], &OutputFormat::OggVorbis, None), None);
+        assert_eq!(duration_ms(&ogg_page(1000, b"\x01opus and then some"), &OutputFormat::OggVorbis, None), None);
+        assert_eq!(duration_ms(&ogg_page(1000, &vorbis_identification(0)), &OutputFormat::OggVorbis, None), None);
+        // Lost the end of its last page
+        let mut audio = ogg_page(0, &vorbis_identification(24000));
+        audio.extend_from_slice(b"OggS\x00\x00\x10");
+        assert_eq!(duration_ms(&audio, &OutputFormat::OggVorbis, None), None);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,86 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket::serde::json::{json, Value};
+
+use crate::config;
+
+const DEFAULT_NAMESPACE: &str = "ipa_server";
+
+// CloudWatch's Embedded Metric Format, JSON log lines on stdout that CloudWatch Logs turns into metrics without a scraper.
+// See https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html
+pub struct Emf {
+    namespace: String,
+}
+
+impl Emf {
+    // None unless IPA_METRICS=emf
+    pub fn from_env() -> Option<Self> {
+        if !config::env_or("IPA_METRICS", String::new()).eq_ignore_ascii_case("emf") {
+            return None;
+        }
+
+        Some(Emf { namespace: config::env_or("IPA_METRICS_NAMESPACE", DEFAULT_NAMESPACE.to_string()) })
+    }
+
+    // One line per event, CloudWatch does the aggregating. The dimensions are the same fixed sets as the Prometheus labels.
+    pub fn emit(&self, name: &str, unit: &str, value: f64, dimensions: &[(&str, &str)]) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+        println!("{}", self.line(timestamp, name, unit, value, dimensions));
+    }
+
+    fn line(&self, timestamp: u64, name: &str, unit: &str, value: f64, dimensions: &[(&str, &str)]) -> Value {
+        let mut line = json!({
+            "_aws": {
+                "Timestamp": timestamp,
+                "CloudWatchMetrics": [{
+                    "Namespace": self.namespace,
+                    "Dimensions": [dimensions.iter().map(|(dimension, _)| *dimension).collect::<Vec<_>>()],
+                    "Metrics": [{ "Name": name, "Unit": unit }],
+                }],
+            },
+        });
+        if let Some(line) = line.as_object_mut() {
+            for (dimension, dimension_value) in dimensions {
+                line.insert(dimension.to_string(), Value::from(*dimension_value));
+            }
+            line.insert(name.to_string(), Value::from(value));
+        }
+
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_declares_what_its_values_are() {
+        let emf = Emf { namespace: "ipa_test".to_string() };
+        let line = emf.line(1_700_000_000_000, "SynthesisSuccesses", "Count", 1.0, &[("language", "English"), ("engine", "neural")]);
+        assert_eq!(line, json!({
+            "_aws": {
+                "Timestamp": 1_700_000_000_000u64,
+                "CloudWatchMetrics": [{
+                    "Namespace": "ipa_test",
+                    "Dimensions": [["language", "engine"]],
+                    "Metrics": [{ "Name": "SynthesisSuccesses", "Unit": "Count" }],
+                }],
+            },
+            "language": "English",
+            "engine": "neural",
+            "SynthesisSuccesses": 1.0,
+        }));
+    }
+
+    #[test]
+    fn no_dimensions_is_one_empty_set() {
+        let emf = Emf { namespace: DEFAULT_NAMESPACE.to_string() };
+        let line = emf.line(0, "PollyLatency", "Milliseconds", 12.5, &[]);
+        assert_eq!(line["_aws"]["CloudWatchMetrics"][0]["Dimensions"], json!([[]]));
+        assert_eq!(line["_aws"]["CloudWatchMetrics"][0]["Namespace"], "ipa_server");
+        assert_eq!(line["PollyLatency"], 12.5);
+        // Everything else is under _aws
+        assert_eq!(line.as_object().map(|line| line.len()), Some(2));
+    }
+}
\ No newline at end of file
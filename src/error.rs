@@ -0,0 +1,112 @@
+use rocket::http::{Header, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::data::Limits;
+use rocket::Request;
+use rocket_validation::{CachedValidationErrors, ValidationErrors};
+
+use crate::logging::ErrorCode;
+use crate::MIN_IPA_LENGTH;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ApiError {
+    #[serde(skip)]
+    status: Status,
+    code: &'static str,
+    message: String,
+    // For a field that only takes certain values, which ones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed: Option<Vec<String>>,
+    // Seconds, sent as Retry-After
+    #[serde(skip)]
+    retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new(status: Status, code: &'static str, message: String) -> Self {
+        ApiError { status, code, message, field: None, allowed: None, retry_after: None }
+    }
+
+    pub fn bad_request(code: &'static str, message: String) -> Self {
+        Self::new(Status::BadRequest, code, message)
+    }
+
+    // A 400 listing what the field takes, so clients don't have to go digging in the docs
+    pub fn unsupported_value(code: &'static str, field: &'static str, value: &str, allowed: &[&str]) -> Self {
+        let mut error = Self::bad_request(code, format!("{value} isn't a supported {field}. Use one of {}", allowed.join(", ")));
+        error.field = Some(field);
+        error.allowed = Some(allowed.iter().map(|value| value.to_string()).collect());
+        error
+    }
+
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    #[cfg(test)]
+    pub fn retry_after(&self) -> Option<u64> {
+        self.retry_after
+    }
+
+    pub fn from_validation_errors(errors: &ValidationErrors) -> Self {
+        // Only the minimum gets checked here, the maximum depends on the language
+        if let Some(ipa_errors) = errors.field_errors().get("ipa") {
+            if ipa_errors.iter().any(|error| error.code == "blank") {
+                return ApiError::bad_request("ipa_empty", "IPA is empty or only whitespace".to_string());
+            }
+            return ApiError::bad_request("ipa_too_short", format!("IPA must be at least {MIN_IPA_LENGTH} character long"));
+        }
+
+        ApiError::bad_request("invalid_request", errors.to_string())
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        request.local_cache(|| Some(ErrorCode(self.code)));
+        let status = self.status;
+        let retry_after = self.retry_after;
+        let mut response = Response::build_from(Json(self).respond_to(request)?);
+        response.status(status);
+        if let Some(retry_after) = retry_after {
+            response.header(Header::new("Retry-After", retry_after.to_string()));
+        }
+
+        response.ok()
+    }
+}
+
+// Stands in for rocket_validation's catcher so validation failures look like every other error
+#[catch(400)]
+pub fn bad_request_catcher(request: &Request) -> ApiError {
+    if let Some(validation_errors) = &request.local_cache(|| CachedValidationErrors(None)).0 {
+        return ApiError::from_validation_errors(validation_errors);
+    }
+
+    ApiError::bad_request("bad_request", "The request could not be understood by the server".to_string())
+}
+
+// What Rocket answers when the JSON parses but doesn't fit RequestData, like a string for seed
+#[catch(422)]
+pub fn unprocessable_entity_catcher() -> ApiError {
+    ApiError::bad_request("invalid_json", "The body doesn't match the request format, check the field types. GET /help lists the fields".to_string())
+}
+
+#[catch(413)]
+pub fn payload_too_large_catcher(request: &Request) -> ApiError {
+    let limit = request.limits().get("json").unwrap_or(Limits::JSON);
+    ApiError::new(Status::PayloadTooLarge, "payload_too_large", format!("Request body can be at most {limit}"))
+}
\ No newline at end of file
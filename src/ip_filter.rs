@@ -0,0 +1,125 @@
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::Request;
+
+use crate::config;
+use crate::error::ApiError;
+
+// A single address or a CIDR block like 203.0.113.0/24
+#[derive(Debug, PartialEq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_length: u32,
+}
+
+impl IpRange {
+    pub fn parse(range: &str) -> Option<Self> {
+        let (address, prefix_length) = match range.split_once('/') {
+            Some((address, prefix_length)) => (address.trim().parse::<IpAddr>().ok()?, Some(prefix_length.trim().parse::<u32>().ok()?)),
+            None => (range.trim().parse::<IpAddr>().ok()?, None),
+        };
+
+        let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_length = prefix_length.unwrap_or(max_prefix_length);
+        if prefix_length > max_prefix_length {
+            return None;
+        }
+
+        Some(IpRange { network: address, prefix_length })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // So an IPv4 client on a dual-stack socket still matches IPv4 ranges
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_length).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_length).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// See config::env_list_and_file
+pub fn load_ranges(list_variable: &str, file_variable: &str) -> Vec<IpRange> {
+    config::env_list_and_file(list_variable, file_variable).iter()
+        .filter_map(|entry| {
+            let range = IpRange::parse(entry);
+            if range.is_none() {
+                warn!("Ignoring {entry} in {list_variable}/{file_variable}, expected an IP address or CIDR block");
+            }
+            range
+        })
+        .collect()
+}
+
+// Clients that are refused outright, before even being rate limited
+#[derive(Clone)]
+pub struct IpDenylist(Arc<RwLock<Vec<IpRange>>>);
+
+impl IpDenylist {
+    pub fn from_env() -> Self {
+        IpDenylist(Arc::new(RwLock::new(load_ranges("IPA_IP_DENYLIST", "IPA_IP_DENYLIST_FILE"))))
+    }
+
+    // So a denylist file can be edited without a restart: kill -HUP <pid>
+    #[cfg(unix)]
+    pub fn reload_on_hangup(&self) {
+        use rocket::tokio::signal::unix::{signal, SignalKind};
+
+        let denylist = self.clone();
+        rocket::tokio::spawn(async move {
+            let mut hangups = match signal(SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(err) => {
+                    warn!("Can't listen for SIGHUP, the IP denylist will only load at startup: {err}");
+                    return;
+                }
+            };
+            while hangups.recv().await.is_some() {
+                denylist.reload();
+            }
+        });
+    }
+
+    // Returns whether it's any different
+    pub fn reload(&self) -> bool {
+        let ranges = load_ranges("IPA_IP_DENYLIST", "IPA_IP_DENYLIST_FILE");
+        info!("Reloaded the IP denylist, {} entries", ranges.len());
+        let mut current = self.0.write().unwrap();
+        let changed = *current != ranges;
+        *current = ranges;
+        changed
+    }
+
+    fn denies(&self, ip: IpAddr) -> bool {
+        self.0.read().unwrap().iter().any(|range| range.contains(ip))
+    }
+}
+
+// Goes before the other guards on a route so a denied client costs as little as possible
+pub struct NotDenied;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for NotDenied {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match (request.rocket().state::<IpDenylist>(), request.client_ip()) {
+            (Some(denylist), Some(ip)) if denylist.denies(ip) => request::Outcome::Error((Status::Forbidden, ())),
+            _ => request::Outcome::Success(NotDenied),
+        }
+    }
+}
+
+#[catch(403)]
+pub fn forbidden_catcher() -> ApiError {
+    ApiError::new(Status::Forbidden, "forbidden", "This client isn't allowed to use the server".to_string())
+}
\ No newline at end of file
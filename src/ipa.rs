@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+lazy_static! {
+    // Letters each language's transcriptions on Wikipedia normally use, going by its Help:IPA page, plus
+    // the common variants of them (ɹ and r, ɡ and g, ...). Stress, length, tones and diacritics aren't
+    // letters, so they're never flagged and don't need listing.
+    static ref INVENTORIES: HashMap<&'static str, &'static str> = HashMap::from([
+        ("English", "abdefghijklmnoprstuvwxzæçðŋɐɑɒɔəɚɛɜɝɡɨɪɫɹɾʃʉʊʌʍʒʔθɵᵻʧʤ"),
+        ("French", "abdefghijklmnoprstuvwyzøŋœɑɔəɛɡɥɲʁʃʒ"),
+        ("Standard German", "abdefghijklmnoprstuvxyzçøŋœɐɔəɛɡɪɾʁʃʊʏʒʔχ"),
+        ("Spanish", "abdefghijklmnoprstuwxzðŋɟɡɣɲɾʃʎʝʒβθʧʤ"),
+        ("Italian", "abdefghijklmnoprstuvwzŋɔəɛɡɱɲʃʎʒʧʤ")
+    ]);
+}
+
+// Deliberately lenient: anything that shows up in IPA transcriptions on Wikipedia should pass,
+// including precomposed accented letters people use for tones
+fn is_ipa_character(character: char) -> bool {
+    matches!(character,
+        'a'..='z'
+        | ' ' | '.' | '|' | '\u{2016}' // syllable break, minor and major group
+        | '\u{00C0}'..='\u{024F}' // Latin-1 supplement and Latin extended letters (æ, ç, ð, ø, ħ, ŋ, œ, ...)
+        | '\u{0250}'..='\u{02AF}' // IPA extensions
+        | '\u{02B0}'..='\u{02FF}' // spacing modifiers: stress, length, tone letters, ʰ, ʲ, ...
+        | '\u{0300}'..='\u{036F}' // combining diacritics, including the tie bars
+        | '\u{03B2}' | '\u{03B8}' | '\u{03C7}' // β, θ, χ
+        | '\u{1D00}'..='\u{1DBF}' // phonetic extensions
+        | '\u{203F}' // ‿ linking
+        | '\u{2191}' | '\u{2193}' | '\u{2197}' | '\u{2198}' // ↑ ↓ ↗ ↘ up/downstep and global rise/fall
+    ) && character != '\u{00D7}' && character != '\u{00F7}'
+}
+
+// Returns the offending characters, each only once, in the order they first appear
+pub fn validate_ipa(ipa: &str) -> Result<(), Vec<char>> {
+    let mut invalid_characters = Vec::new();
+    for character in ipa.chars() {
+        if !is_ipa_character(character) && !invalid_characters.contains(&character) {
+            invalid_characters.push(character);
+        }
+    }
+
+    if invalid_characters.is_empty() {
+        Ok(())
+    } else {
+        Err(invalid_characters)
+    }
+}
+
+// Stress marks, syllable breaks and the like on their own leave Polly nothing to say.
+// The symbols are the X-SAMPA sounds that aren't letters or digits (@ is a schwa).
+pub fn has_sounds(transcription: &str) -> bool {
+    transcription.chars().any(|character| is_letter(character) || character.is_numeric() || matches!(character, '@' | '{' | '}' | '&' | '?'))
+}
+
+// Modifier letters (ʰ, ʲ, ˈ, ...) count as alphabetic but are more like diacritics here
+fn is_letter(character: char) -> bool {
+    character.is_alphabetic() && !matches!(character, '\u{02B0}'..='\u{02FF}' | '\u{1D2C}'..='\u{1D6A}')
+}
+
+// Look-alikes people type or paste in place of the real IPA symbols. Only ones that can't mean
+// anything else in IPA, so e.g. a comma isn't taken for secondary stress.
+pub fn normalize(transcription: &str) -> String {
+    transcription.chars()
+        .map(|character| match character {
+            '\'' | '\u{2019}' | '\u{00B4}' | '\u{02B9}' => '\u{02C8}', // ' ’ ´ ʹ to ˈ primary stress
+            ':' | '\u{A789}' => '\u{02D0}', // : ꞉ to ː length
+            'g' => '\u{0261}', // ɡ, the IPA letter is the single-storey one
+            _ => character,
+        })
+        .collect()
+}
+
+// Letters that aren't in the language's usual inventory, each only once in the order they first
+// appear. None for languages without an inventory to go on.
+pub fn unexpected_symbols(language_name: &str, transcription: &str) -> Option<Vec<char>> {
+    let inventory = INVENTORIES.get(language_name)?;
+    let mut unexpected = Vec::new();
+    for character in transcription.chars() {
+        if is_letter(character) && !inventory.contains(character) && !unexpected.contains(&character) {
+            unexpected.push(character);
+        }
+    }
+
+    Some(unexpected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_every_kind_of_ipa_symbol() {
+        // Stress, length and half-length
+        assert_eq!(validate_ipa("ˈkæt ˌbiːt ˈbiˑt"), Ok(()));
+        // Tie bars above and below
+        assert_eq!(validate_ipa("t͡ʃ d͜ʒ"), Ok(()));
+        // Aspiration, nasalization, voicelessness, syllabic consonants and no audible release
+        assert_eq!(validate_ipa("kʰ ɔ̃ n̥ n̩ t̚"), Ok(()));
+        // Syllable breaks, groups, linking, tone letters, up/downstep and precomposed tones
+        assert_eq!(validate_ipa("ma.ma | ŋ‖ a‿b ma˥˩ ↑a↓ ↗ ↘ mǎ mà"), Ok(()));
+        assert_eq!(validate_ipa("βθχ ɸɣʕ ᴀᵻ"), Ok(()));
+    }
+
+    #[test]
+    fn rejects_each_character_once_in_order() {
+        assert_eq!(validate_ipa("hello, WORLD!"), Err(vec![',', 'W', 'O', 'R', 'L', 'D', '!']));
+        assert_eq!(validate_ipa("1ə2ə1"), Err(vec!['1', '2']));
+        // Math symbols sit inside the Latin-1 range but aren't letters
+        assert_eq!(validate_ipa("a×b÷a×"), Err(vec!['×', '÷']));
+        assert_eq!(validate_ipa("<b/>"), Err(vec!['<', '/', '>']));
+    }
+
+    #[test]
+    fn only_suprasegmentals_is_no_sounds() {
+        assert!(!has_sounds("ˈ.ˌː |‖"));
+        assert!(!has_sounds("ʰʲ˥"));
+        assert!(!has_sounds(""));
+        assert!(has_sounds("ˈə"));
+        assert!(has_sounds("ŋ̍"));
+        // X-SAMPA sounds that aren't letters
+        for symbol in ["@", "{", "}", "&", "?"] {
+            assert!(has_sounds(symbol), "{symbol}");
+        }
+        assert!(!has_sounds("\"%"));
+    }
+
+    #[test]
+    fn normalizes_look_alikes() {
+        assert_eq!(normalize("h@'loU"), "h@ˈloU");
+        assert_eq!(normalize("ˈhæpi’"), "ˈhæpiˈ");
+        assert_eq!(normalize("ɡ´ʹ"), "ɡˈˈ");
+        assert_eq!(normalize("bi:t"), "biːt");
+        assert_eq!(normalize("bi꞉t"), "biːt");
+        assert_eq!(normalize("gʊd"), "ɡʊd");
+    }
+
+    #[test]
+    fn flags_letters_outside_the_inventory() {
+        // A click and a pharyngeal mean nothing in English
+        assert_eq!(unexpected_symbols("English", "ǃhəˈloʊħ"), Some(vec!['ǃ', 'ħ']));
+        assert_eq!(unexpected_symbols("English", "ˈθɪŋkɪŋ"), Some(vec![]));
+        // ʁ is French and German, but not English or Spanish
+        assert_eq!(unexpected_symbols("French", "ʁu.ʒ"), Some(vec![]));
+        assert_eq!(unexpected_symbols("Standard German", "ʁoːt"), Some(vec![]));
+        assert_eq!(unexpected_symbols("English", "ʁed"), Some(vec!['ʁ']));
+        assert_eq!(unexpected_symbols("Spanish", "ʁoxo"), Some(vec!['ʁ']));
+        assert_eq!(unexpected_symbols("Spanish", "ˈpeɾo"), Some(vec![]));
+        assert_eq!(unexpected_symbols("Italian", "ˈkaːza"), Some(vec![]));
+        assert_eq!(unexpected_symbols("Italian", "θeːo"), Some(vec!['θ']));
+    }
+
+    #[test]
+    fn diacritics_and_suprasegmentals_are_never_flagged() {
+        assert_eq!(unexpected_symbols("English", "ˈkʰæːt̚ ˌʰ"), Some(vec![]));
+        assert_eq!(unexpected_symbols("French", "bɔ̃ʒuʁ"), Some(vec![]));
+    }
+
+    #[test]
+    fn each_symbol_is_reported_once() {
+        assert_eq!(unexpected_symbols("English", "ħaħaħ"), Some(vec!['ħ']));
+    }
+
+    #[test]
+    fn no_inventory_no_warnings() {
+        assert_eq!(unexpected_symbols("Mandarin", "ǃħ"), None);
+    }
+
+    #[test]
+    fn leaves_real_ipa_alone() {
+        for transcription in ["həˈloʊ", "ˌɪntəˈnæʃənəl", "biːt", "tʰɔːk", "ɡʊd", "a.b|c", "ʃʌt, ʌp"] {
+            assert_eq!(normalize(transcription), transcription);
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+
+use aws_sdk_polly::model::{Engine, LanguageCode};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::Request;
+
+use crate::{config, MAX_IPA_LENGTH, NAME_TO_ENGINE};
+
+lazy_static! {
+    // Wikipedia IPA language page to AWS LanguageCode, unless IPA_LANGUAGE_MAP says otherwise
+    static ref DEFAULT_LANGUAGE_TO_CODE: HashMap<&'static str, LanguageCode> = HashMap::from([
+        ("Arabic", LanguageCode::Arb),
+        ("Catalan", LanguageCode::CaEs),
+        ("Mandarin", LanguageCode::CmnCn),
+        ("Welsh", LanguageCode::CyGb),
+        ("Danish", LanguageCode::DaDk),
+        ("Standard German", LanguageCode::DeAt),
+        ("English", LanguageCode::EnUs),
+        ("Spanish", LanguageCode::EsEs),
+        ("French", LanguageCode::FrCa),
+        ("Hindi and Urdu", LanguageCode::HiIn),
+        ("Icelandic", LanguageCode::IsIs),
+        ("Italian", LanguageCode::ItIt),
+        ("Japanese", LanguageCode::JaJp),
+        ("Korean", LanguageCode::KoKr),
+        ("Norwegian", LanguageCode::NbNo),
+        ("Dutch", LanguageCode::NlNl),
+        ("Polish", LanguageCode::PlPl),
+        ("Portuguese", LanguageCode::PtBr),
+        ("Romanian", LanguageCode::RoRo),
+        ("Russian", LanguageCode::RuRu),
+        ("Swedish", LanguageCode::SvSe),
+        ("Turkish", LanguageCode::TrTr)
+    ]);
+
+    // What people tend to type instead of the names above, lowercase
+    static ref LANGUAGE_ALIASES: HashMap<&'static str, &'static str> = HashMap::from([
+        ("german", "Standard German"),
+        ("high german", "Standard German"),
+        ("chinese", "Mandarin"),
+        ("mandarin chinese", "Mandarin"),
+        ("standard chinese", "Mandarin"),
+        ("brazilian portuguese", "Portuguese"),
+        ("hindi", "Hindi and Urdu"),
+        ("urdu", "Hindi and Urdu"),
+        ("hindustani", "Hindi and Urdu"),
+        ("bokmål", "Norwegian"),
+        ("norwegian bokmål", "Norwegian"),
+        ("castilian", "Spanish"),
+        ("american english", "English"),
+        ("modern standard arabic", "Arabic"),
+        ("canadian french", "French")
+    ]);
+
+    // Tone marks and long compounds legitimately need more room than MAX_IPA_LENGTH
+    static ref DEFAULT_MAX_IPA_LENGTHS: HashMap<&'static str, u64> = HashMap::from([
+        ("Mandarin", 100),
+        ("Standard German", 80)
+    ]);
+
+    // For carrier, {} is where the phonemes go. Languages without one need carrier_template in the request.
+    static ref DEFAULT_CARRIER_TEMPLATES: HashMap<&'static str, &'static str> = HashMap::from([
+        ("English", "The word is {}."),
+        ("French", "Le mot est {}."),
+        ("Standard German", "Das Wort ist {}."),
+        ("Spanish", "La palabra es {}."),
+        ("Italian", "La parola è {}.")
+    ]);
+}
+
+#[derive(PartialEq)]
+pub struct LanguageMap {
+    languages: HashMap<String, LanguageCode>,
+    // Normalized name or alias to the name as it appears in languages
+    lookup: HashMap<String, String>,
+    max_ipa_lengths: HashMap<String, u64>,
+    // Used when a request doesn't ask for an engine and the voice has it
+    default_engines: HashMap<String, Engine>,
+    carrier_templates: HashMap<String, String>,
+    // For requests that don't say, by its name in languages
+    default_language: Option<String>,
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+impl LanguageMap {
+    fn new(languages: HashMap<String, LanguageCode>) -> Self {
+        let mut lookup: HashMap<String, String> = languages.keys().map(|name| (normalize(name), name.clone())).collect();
+        for (alias, canonical) in LANGUAGE_ALIASES.iter() {
+            // A custom map might not have the language an alias points at, or might use the alias as a real name
+            if languages.contains_key(*canonical) {
+                lookup.entry(alias.to_string()).or_insert_with(|| canonical.to_string());
+            }
+        }
+
+        let mut language_map = LanguageMap { languages, lookup, max_ipa_lengths: HashMap::new(), default_engines: HashMap::new(), carrier_templates: HashMap::new(), default_language: None };
+        // A custom map doesn't have to have these
+        for (name, max_length) in DEFAULT_MAX_IPA_LENGTHS.iter() {
+            language_map.set_max_ipa_length(name, *max_length);
+        }
+        // Name=length pairs, e.g. IPA_MAX_IPA_LENGTHS=Mandarin=120,Japanese=80
+        for limit in config::env_list("IPA_MAX_IPA_LENGTHS") {
+            match limit.split_once('=').map(|(name, max_length)| (name, max_length.trim().parse())) {
+                Some((name, Ok(max_length))) => {
+                    if !language_map.set_max_ipa_length(name, max_length) {
+                        warn!("Ignoring {limit} in IPA_MAX_IPA_LENGTHS, {name} isn't a known language");
+                    }
+                }
+                _ => warn!("Ignoring {limit} in IPA_MAX_IPA_LENGTHS, expected Language=length"),
+            }
+        }
+
+        // Name=engine pairs, e.g. IPA_DEFAULT_ENGINES=English=neural,Icelandic=standard
+        for default_engine in config::env_list("IPA_DEFAULT_ENGINES") {
+            match default_engine.split_once('=').map(|(name, engine_name)| (name, NAME_TO_ENGINE.get(engine_name.trim()))) {
+                Some((name, Some(engine))) => match language_map.get(name) {
+                    Some((name, _)) => {
+                        language_map.default_engines.insert(name.to_string(), engine.clone());
+                    }
+                    None => warn!("Ignoring {default_engine} in IPA_DEFAULT_ENGINES, {name} isn't a known language"),
+                },
+                _ => warn!("Ignoring {default_engine} in IPA_DEFAULT_ENGINES, expected Language=standard, neural or generative"),
+            }
+        }
+
+        for (name, template) in DEFAULT_CARRIER_TEMPLATES.iter() {
+            if let Some((name, _)) = language_map.get(name) {
+                language_map.carrier_templates.insert(name.to_string(), template.to_string());
+            }
+        }
+        // Templates can have commas in them, so a file rather than a list
+        let path = config::env_or("IPA_CARRIER_TEMPLATES", String::new());
+        if !path.is_empty() {
+            match load_carrier_templates(&path) {
+                Ok(templates) => {
+                    for (name, template) in templates {
+                        match language_map.get(&name) {
+                            Some((name, _)) if template.matches("{}").count() == 1 => {
+                                language_map.carrier_templates.insert(name.to_string(), template);
+                            }
+                            Some(_) => warn!("Ignoring the carrier template for {name} in {path}, it needs exactly one {{}}"),
+                            None => warn!("Ignoring the carrier template for {name} in {path}, it isn't a known language"),
+                        }
+                    }
+                }
+                Err(err) => error!("Failed to load carrier templates from {path}: {err}"),
+            }
+        }
+
+        language_map
+    }
+
+    fn set_default_language(&mut self, name: &str) -> bool {
+        let Some((name, _)) = self.get(name) else { return false };
+        self.default_language = Some(name.to_string());
+        true
+    }
+
+    pub fn default_language(&self) -> Option<&str> {
+        self.default_language.as_deref()
+    }
+
+    pub fn carrier_template(&self, name: &str) -> Option<&str> {
+        self.carrier_templates.get(name).map(String::as_str)
+    }
+
+    pub fn default_engine(&self, name: &str) -> Option<&Engine> {
+        self.default_engines.get(name)
+    }
+
+    fn set_max_ipa_length(&mut self, name: &str, max_length: u64) -> bool {
+        let Some((name, _)) = self.get(name) else { return false };
+        self.max_ipa_lengths.insert(name.to_string(), max_length);
+        true
+    }
+
+    pub fn max_ipa_length(&self, name: &str) -> u64 {
+        self.max_ipa_lengths.get(name).copied().unwrap_or(MAX_IPA_LENGTH)
+    }
+
+    // Just the languages that don't use MAX_IPA_LENGTH
+    pub fn max_ipa_lengths(&self) -> &HashMap<String, u64> {
+        &self.max_ipa_lengths
+    }
+
+    // Only fails on an IPA_DEFAULT_LANGUAGE that isn't in the map, everything else falls back with a warning
+    pub fn from_env() -> Result<Self, String> {
+        let path = config::env_or("IPA_LANGUAGE_MAP", String::new());
+        let mut language_map = if path.is_empty() {
+            Self::default()
+        } else {
+            match Self::load(&path) {
+                Ok(languages) => {
+                    info!("Loaded {} languages from {path}", languages.languages.len());
+                    languages
+                }
+                Err(err) => {
+                    error!("Failed to load the language map from {path}, using the built in one: {err}");
+                    Self::default()
+                }
+            }
+        };
+
+        let default_language = config::env_or("IPA_DEFAULT_LANGUAGE", String::new());
+        if !default_language.is_empty() && !language_map.set_default_language(&default_language) {
+            return Err(format!("IPA_DEFAULT_LANGUAGE is {default_language}, which isn't a supported language"));
+        }
+
+        Ok(language_map)
+    }
+
+    // A JSON object of language name to AWS language code, e.g. {"English": "en-US"}
+    fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let names_to_codes: HashMap<String, String> = rocket::serde::json::from_str(&std::fs::read_to_string(path)?)?;
+
+        let mut languages = HashMap::new();
+        for (name, code) in names_to_codes {
+            match LanguageCode::from(code.as_str()) {
+                LanguageCode::Unknown(_) => warn!("Skipping {name} in {path}, {code} isn't a language code Polly knows"),
+                language_code => {
+                    languages.insert(name, language_code);
+                }
+            }
+        }
+
+        Ok(Self::new(languages))
+    }
+
+    // Ignores case and surrounding whitespace, and goes through the aliases
+    pub fn get(&self, name: &str) -> Option<(&str, &LanguageCode)> {
+        let canonical = self.lookup.get(&normalize(name))?;
+        self.languages.get_key_value(canonical).map(|(name, code)| (name.as_str(), code))
+    }
+
+    // For "did you mean" in errors. Only offers something reasonably close, anything will be closest to something
+    pub fn closest(&self, name: &str) -> Option<&str> {
+        let name = normalize(name);
+        let max_distance = (name.chars().count() / 3).max(2);
+
+        self.lookup.iter()
+            .map(|(candidate, canonical)| (levenshtein(&name, candidate), canonical.as_str()))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .min()
+            .map(|(_, canonical)| canonical)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &LanguageCode)> {
+        self.languages.iter().map(|(name, code)| (name.as_str(), code))
+    }
+}
+
+impl Default for LanguageMap {
+    fn default() -> Self {
+        Self::new(DEFAULT_LANGUAGE_TO_CODE.iter().map(|(name, code)| (name.to_string(), code.clone())).collect())
+    }
+}
+
+// A JSON object of language name to template, e.g. {"English": "Say {} again."}
+fn load_carrier_templates(path: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    Ok(rocket::serde::json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+// What's managed, swapped out whole by POST /admin/reload
+pub struct SharedLanguageMap(RwLock<Arc<LanguageMap>>);
+
+impl SharedLanguageMap {
+    pub fn new(language_map: LanguageMap) -> Self {
+        SharedLanguageMap(RwLock::new(Arc::new(language_map)))
+    }
+
+    // Returns whether it's any different
+    pub fn replace(&self, language_map: LanguageMap) -> bool {
+        let mut current = self.0.write().unwrap();
+        let changed = **current != language_map;
+        *current = Arc::new(language_map);
+        changed
+    }
+}
+
+// The language map as it was when the request came in, so a reload can't change it halfway through
+pub struct Languages(Arc<LanguageMap>);
+
+impl Deref for Languages {
+    type Target = LanguageMap;
+
+    fn deref(&self) -> &LanguageMap {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Languages {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match request.rocket().state::<SharedLanguageMap>() {
+            Some(shared) => request::Outcome::Success(Languages(shared.0.read().unwrap().clone())),
+            None => request::Outcome::Error((Status::InternalServerError, ())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignores_case_and_whitespace() {
+        let languages = LanguageMap::default();
+        assert_eq!(languages.get("ENGLISH"), Some(("English", &LanguageCode::EnUs)));
+        assert_eq!(languages.get("english"), Some(("English", &LanguageCode::EnUs)));
+        assert_eq!(languages.get(" Mandarin "), Some(("Mandarin", &LanguageCode::CmnCn)));
+        assert_eq!(languages.get("\tMANDARIN CHINESE\n"), Some(("Mandarin", &LanguageCode::CmnCn)));
+        assert_eq!(languages.get("Engl ish"), None);
+        assert_eq!(languages.get(""), None);
+    }
+    #[test]
+    fn aliases_go_to_the_language() {
+        let languages = LanguageMap::default();
+        assert_eq!(languages.get("German"), Some(("Standard German", &LanguageCode::DeAt)));
+        assert_eq!(languages.get("Chinese"), Some(("Mandarin", &LanguageCode::CmnCn)));
+        assert_eq!(languages.get("urdu"), Some(("Hindi and Urdu", &LanguageCode::HiIn)));
+        assert_eq!(languages.get("Bokmål"), Some(("Norwegian", &LanguageCode::NbNo)));
+    }
+
+    #[test]
+    fn aliases_only_point_at_languages_in_the_map() {
+        let languages = LanguageMap::new(HashMap::from([("English".to_string(), LanguageCode::EnGb), ("German".to_string(), LanguageCode::DeDe)]));
+        assert_eq!(languages.get("american english"), Some(("English", &LanguageCode::EnGb)));
+        // Standard German isn't there, so german is just the name
+        assert_eq!(languages.get("german"), Some(("German", &LanguageCode::DeDe)));
+        assert_eq!(languages.get("high german"), None);
+        assert_eq!(languages.get("chinese"), None);
+    }
+
+    #[test]
+    fn closest_suggests_typos() {
+        let languages = LanguageMap::default();
+        assert_eq!(languages.closest("Englsh"), Some("English"));
+        assert_eq!(languages.closest("  FRENHC "), Some("French"));
+        // Through an alias
+        assert_eq!(languages.closest("Germn"), Some("Standard German"));
+        assert_eq!(languages.closest("hindu"), Some("Hindi and Urdu"));
+    }
+
+    #[test]
+    fn closest_breaks_ties_by_name() {
+        let languages = LanguageMap::new(HashMap::from([("Tamil".to_string(), LanguageCode::EnIn), ("Tamal".to_string(), LanguageCode::EnIn)]));
+        for _ in 0..10 {
+            assert_eq!(languages.closest("Tamel"), Some("Tamal"));
+        }
+        // Closer wins over the name
+        assert_eq!(languages.closest("Tamik"), Some("Tamil"));
+    }
+
+    #[test]
+    fn nothing_close_is_no_suggestion() {
+        let languages = LanguageMap::default();
+        assert_eq!(languages.closest("Klingon"), None);
+        assert_eq!(languages.closest("xx"), None);
+        assert_eq!(languages.closest(""), None);
+        // A third of the length is allowed, no more
+        assert_eq!(languages.closest("Porxxxxxxx"), None);
+        assert_eq!(languages.closest("Portuguxxx"), Some("Portuguese"));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,141 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::serde::json::{json, Value};
+use rocket::{Data, Request, Response};
+
+use crate::config;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+pub fn json_enabled() -> bool {
+    config::env_or("IPA_LOG_FORMAT", String::new()).eq_ignore_ascii_case("json")
+}
+
+struct JsonLogger;
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        // Same as Rocket's logger, these are far too chatty outside of debugging
+        let noisy = record.module_path().is_some_and(|module| module.starts_with("hyper") || module.starts_with("rustls"));
+        if !self.enabled(record.metadata()) || (noisy && log::max_level() < log::LevelFilter::Debug) {
+            return;
+        }
+
+        write_line(record.level(), record.target(), &record.args().to_string(), json!({}));
+    }
+
+    fn flush(&self) {}
+}
+
+fn write_line(level: log::Level, target: &str, message: &str, fields: Value) {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |since_epoch| since_epoch.as_secs_f64());
+    let mut line = json!({
+        "timestamp": timestamp,
+        "level": level.as_str(),
+        "target": target,
+        "message": message,
+    });
+    if let (Some(line), Value::Object(fields)) = (line.as_object_mut(), fields) {
+        line.extend(fields);
+    }
+
+    println!("{line}");
+}
+
+// Has to happen before rocket::build(), Rocket leaves an already installed logger alone
+pub fn init() {
+    if json_enabled() && log::set_boxed_logger(Box::new(JsonLogger)).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
+}
+
+pub struct RequestId(pub String);
+
+// What the speak routes tell the request log about a synthesis, set while responding
+pub struct SynthesisDetails {
+    pub language: String,
+    pub voice: String,
+    pub engine: String,
+    pub ipa_length: usize,
+}
+
+pub struct ErrorCode(pub &'static str);
+
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LENGTH
+        && request_id.chars().all(|character| character.is_ascii_alphanumeric() || matches!(character, '-' | '_' | '.'))
+}
+
+// Tags every request with an id (the caller's X-Request-Id if it sent a sane one) and logs one line per request
+pub struct RequestLogger {
+    json: bool,
+}
+
+impl RequestLogger {
+    pub fn from_env() -> Self {
+        RequestLogger { json: json_enabled() }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ids and request logging",
+            kind: Kind::Request | Kind::Response
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        let request_id = match request.headers().get_one(REQUEST_ID_HEADER) {
+            Some(request_id) if is_valid_request_id(request_id) => request_id.to_string(),
+            _ => format!("{:032x}", rand::thread_rng().gen::<u128>()),
+        };
+        request.local_cache(|| RequestId(request_id));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let request_id = &request.local_cache(|| RequestId(String::new())).0;
+        response.set_header(Header::new(REQUEST_ID_HEADER, request_id.clone()));
+
+        let synthesis = request.local_cache(|| None::<SynthesisDetails>);
+        let error_code = request.local_cache(|| None::<ErrorCode>);
+        let outcome = match error_code {
+            Some(ErrorCode(code)) => code,
+            None if response.status().code < 400 => "success",
+            None => response.status().reason().unwrap_or("error"),
+        };
+
+        if self.json {
+            let mut fields = json!({
+                "request_id": request_id,
+                "method": request.method().as_str(),
+                "path": request.uri().path().as_str(),
+                "status": response.status().code,
+                "outcome": outcome,
+            });
+            if let (Some(synthesis), Some(fields)) = (synthesis, fields.as_object_mut()) {
+                fields.insert("language".to_string(), json!(synthesis.language));
+                fields.insert("voice".to_string(), json!(synthesis.voice));
+                fields.insert("engine".to_string(), json!(synthesis.engine));
+                fields.insert("ipa_length".to_string(), json!(synthesis.ipa_length));
+            }
+
+            write_line(log::Level::Info, "ipa_server::request", "request completed", fields);
+        } else {
+            let synthesis = synthesis.as_ref()
+                .map(|synthesis| format!(" language={} voice={} engine={} ipa_length={}", synthesis.language, synthesis.voice, synthesis.engine, synthesis.ipa_length))
+                .unwrap_or_default();
+            info!("{} {} {} request_id={request_id} outcome={outcome}{synthesis}", request.method(), request.uri().path(), response.status().code);
+        }
+    }
+}
\ No newline at end of file
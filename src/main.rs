@@ -4,130 +4,1424 @@ extern crate lazy_static;
 #[macro_use]
 extern crate rocket;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use rand::seq::SliceRandom;
 use rand::SeedableRng;
 
-use aws_config;
-use aws_sdk_polly::{Client, Region};
-use aws_sdk_polly::model::{Engine, LanguageCode, OutputFormat, TextType, VoiceId};
+use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_polly::error::DescribeVoicesError;
+use aws_sdk_polly::types::SdkError;
+use aws_sdk_polly::{Client, Region, RetryConfig};
+use bytes::Bytes;
+use aws_sdk_polly::model::{Engine, Gender, LanguageCode, OutputFormat, SpeechMarkType, TextType, Voice, VoiceId};
+use rocket::form::Errors;
+use rocket::http::{Accept, ContentType, Header, Method, Status};
 use rocket::response::status;
-use rocket::response::stream::ReaderStream;
-use rocket::State;
-use rocket::serde::Deserialize;
-use rocket::serde::json::Json;
-use rocket_governor::{Method, Quota, ReqState, RocketGovernable, RocketGovernor};
+use rocket::response::{self, Responder, Response};
+use rocket::request::{self, FromRequest};
+use rocket::{Request, State};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::serde::json::{Json, Value};
+use rocket::tokio::io::AsyncReadExt;
+use rocket::tokio::sync::{Semaphore, SemaphorePermit};
 use rocket_validation::{Validate, Validated};
+use validator::ValidationError;
+use tracing::{field, Instrument};
 
+mod admin;
+mod auth;
+mod cache;
+mod circuit_breaker;
+#[cfg(feature = "compression")]
+mod compression;
+mod config;
 mod cors;
+mod duration;
+mod emf;
+mod error;
+mod ip_filter;
+mod ipa;
+mod language_map;
+mod logging;
+mod metrics;
+mod range;
+mod rate_limit;
+mod retry;
+mod s3_cache;
+mod serde_fields;
+mod shutdown;
+mod single_flight;
+mod ssml;
+mod synthesizer;
+mod voice_selection;
+#[cfg(feature = "otel")]
+mod telemetry;
+#[cfg(feature = "websocket")]
+mod websocket;
+
+use auth::{ApiKey, ApiKeys};
+use cache::LruCache;
+use circuit_breaker::CircuitBreaker;
+use config::Settings;
+use error::ApiError;
+use ip_filter::{IpDenylist, NotDenied};
+use language_map::{LanguageMap, Languages, SharedLanguageMap};
+use metrics::Metrics;
+use range::ByteRange;
+use rate_limit::{RateLimit, RateLimitStatus, RateLimits};
+use s3_cache::S3Cache;
+use single_flight::SingleFlight;
+use synthesizer::{AudioStream, LimitedReader, PollySynthesizer, SpeechSynthesizer};
+use voice_selection::{RoundRobin, Strategy};
+
+// In characters rather than bytes, since most IPA symbols are multi-byte in UTF-8.
+// The maximum can be raised per language, see LanguageMap::max_ipa_length.
+const MIN_IPA_LENGTH: u64 = 1;
+const MAX_IPA_LENGTH: u64 = 50;
+const MAX_CARRIER_TEMPLATE_LENGTH: usize = 200;
+// text_type text, which has far fewer characters a sound than IPA
+const MAX_TEXT_LENGTH: usize = 500;
+const MAX_LANGUAGE_LENGTH: usize = 64;
+// Doubles as the IPA minor group break, which is a pause between words anyway
+const WORD_SEPARATOR: char = '|';
+
+const DEFAULT_CACHE_CAPACITY: usize = 1000;
+const DEFAULT_AWS_REGION: &str = "eu-west-2";
+const DEFAULT_SYNTHESIS_TIMEOUT_SECS: u64 = 10;
+const MAX_BATCH_SIZE: usize = 20;
+// Rocket allows 1 MiB of JSON. A request is a couple hundred bytes, this leaves room for a full batch.
+const DEFAULT_MAX_BODY_BYTES: u64 = 8 * 1024;
+// Both limits are Polly's
+const MAX_LEXICONS: usize = 5;
+const MAX_LEXICON_NAME_LENGTH: usize = 20;
+const STREAM_CHUNK_SIZE: usize = 1024;
+// Minutes of PCM, far more than any request should produce
+const DEFAULT_MAX_AUDIO_BYTES: usize = 5 * 1024 * 1024;
+// A few seconds of audio in any format, which is plenty for a word or two
+const MAX_PREVIEW_BYTES: usize = 256 * 1024;
+const VOICE_RETRY_SECS: u64 = 30;
+const DEFAULT_MAX_CONCURRENT_SYNTHESES: usize = 20;
+const PERMIT_WAIT_MS: u64 = 1000;
+const DEFAULT_VOICE_REFRESH_SECS: u64 = 60 * 60;
+// "hello", see self_test
+const SELF_TEST_IPA: &str = "həˈloʊ";
+
+// Every key in FORMAT_TO_OUTPUT, in the order they're listed to clients
+const FORMAT_NAMES: [&str; 3] = ["ogg", "mp3", "pcm"];
+// The keys of NAME_TO_ENGINE and NAME_TO_SPEECH_MARK, in the order error messages list them
+const ENGINE_NAMES: [&str; 3] = ["standard", "neural", "generative"];
+const SPEECH_MARK_NAMES: [&str; 4] = ["sentence", "ssml", "viseme", "word"];
 
 lazy_static! {
-    // Wikipedia IPA language page to AWS LanguageCode
-    static ref LANGUAGE_TO_CODE: HashMap<&'static str, LanguageCode> = HashMap::from([
-        ("Arabic", LanguageCode::Arb),
-        ("Catalan", LanguageCode::CaEs),
-        ("Mandarin", LanguageCode::CmnCn),
-        ("Welsh", LanguageCode::CyGb),
-        ("Danish", LanguageCode::DaDk),
-        ("Standard German", LanguageCode::DeAt),
-        ("English", LanguageCode::EnUs),
-        ("Spanish", LanguageCode::EsEs),
-        ("French", LanguageCode::FrCa),
-        ("Hindi and Urdu", LanguageCode::HiIn),
-        ("Icelandic", LanguageCode::IsIs),
-        ("Italian", LanguageCode::ItIt),
-        ("Japanese", LanguageCode::JaJp),
-        ("Korean", LanguageCode::KoKr),
-        ("Norwegian", LanguageCode::NbNo),
-        ("Dutch", LanguageCode::NlNl),
-        ("Polish", LanguageCode::PlPl),
-        ("Portuguese", LanguageCode::PtBr),
-        ("Romanian", LanguageCode::RoRo),
-        ("Russian", LanguageCode::RuRu),
-        ("Swedish", LanguageCode::SvSe),
-        ("Turkish", LanguageCode::TrTr)
+    // Request format name to AWS OutputFormat and the Content-Type it's served as
+    static ref FORMAT_TO_OUTPUT: HashMap<&'static str, (OutputFormat, ContentType)> = HashMap::from([
+        ("ogg", (OutputFormat::OggVorbis, ContentType::new("audio", "ogg"))),
+        ("mp3", (OutputFormat::Mp3, ContentType::new("audio", "mpeg"))),
+        ("pcm", (OutputFormat::Pcm, ContentType::new("audio", "pcm")))
+    ]);
+
+    // Polly documents <phoneme> as supported by standard and neural in every language. Generative is
+    // newer than the SDK, hence the string, and doesn't take phonemes everywhere, see fetch_speech
+    static ref NAME_TO_ENGINE: HashMap<&'static str, Engine> = HashMap::from([
+        ("standard", Engine::Standard),
+        ("neural", Engine::Neural),
+        ("generative", Engine::from("generative"))
+    ]);
+
+    static ref NAME_TO_SPEECH_MARK: HashMap<&'static str, SpeechMarkType> = HashMap::from([
+        ("sentence", SpeechMarkType::Sentence),
+        ("ssml", SpeechMarkType::Ssml),
+        ("viseme", SpeechMarkType::Viseme),
+        ("word", SpeechMarkType::Word)
     ]);
 }
 
-pub struct RateLimitGuard;
+#[derive(Debug, Deserialize, FromForm, Validate)]
+#[serde(crate = "rocket::serde")]
+pub struct RequestData {
+    #[validate(custom = "not_blank", length(min = "MIN_IPA_LENGTH"))]
+    ipa: String,
+    // Falls back to IPA_DEFAULT_LANGUAGE when missing or empty
+    language: Option<String>,
+    format: Option<String>,
+    voice: Option<String>,
+    engine: Option<String>,
+    rate: Option<String>,
+    pitch: Option<String>,
+    volume: Option<String>,
+    sample_rate: Option<String>,
+    alphabet: Option<String>,
+    seed: Option<u64>,
+    // Say the IPA inside the language's carrier sentence, see LanguageMap::carrier_template
+    carrier: Option<bool>,
+    // A carrier sentence of the client's own, with {} where the IPA goes
+    carrier_template: Option<String>,
+    // whispered or drc, see ssml::EFFECT_NAMES
+    effect: Option<String>,
+    // ipa, or text to have Polly read ipa as ordinary text
+    text_type: Option<String>,
+    // random, round-robin or first, defaulting to IPA_VOICE_STRATEGY
+    strategy: Option<String>,
+    gender: Option<String>,
+    // A full language code like en-GB, to pick between voices of the same language
+    variant: Option<String>,
+    // Adds X-Audio-Duration-Ms to audio responses
+    include_duration: Option<bool>,
+    // Adds X-IPA-Warnings for letters that aren't usually in the language
+    strict: Option<bool>,
+    // A voice that's no longer loaded gets a 400 instead of another voice of the language
+    strict_voice: Option<bool>,
+    // Swaps common look-alikes for the IPA symbols, see ipa::normalize
+    normalize: Option<bool>,
+    // Pause between | separated words, capped at ssml::MAX_BREAK_MS
+    break_ms: Option<u64>,
+    // Comma-separated names of Polly lexicons to apply
+    lexicon_names: Option<String>,
+    // Comma-separated, only used by /speak/marks
+    speech_marks: Option<String>,
+}
 
-impl<'r> RocketGovernable<'r> for RateLimitGuard {
-    fn quota(_method: Method, _route_name: &str) -> Quota {
-        Quota::per_hour(Self::nonzero(100u32))
+// So "" and "  " get their own error rather than the length one, see ApiError::from_validation_errors
+fn not_blank(value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        return Err(ValidationError::new("blank"));
     }
+    Ok(())
+}
 
-    fn limit_info_allow(_method: Option<Method>, _route_name: Option<&str>, _state: &ReqState) -> bool {
-        true
-    }
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct LanguageInfo {
+    name: String,
+    code: String,
+    // Whether any speakers were actually loaded for it
+    available: bool,
 }
 
-#[derive(Debug, Deserialize, Validate)]
+#[derive(Serialize)]
 #[serde(crate = "rocket::serde")]
-pub struct RequestData {
-    #[validate(length(min = 1, max = 50))]
-    ipa: String,
+struct Help {
+    endpoint: &'static str,
+    required: Vec<&'static str>,
+    optional: Vec<&'static str>,
+    min_ipa_length: u64,
+    // Per | separated word
+    max_ipa_length: u64,
+    max_ipa_lengths: HashMap<String, u64>,
+    max_text_length: usize,
+    max_break_ms: u64,
+    max_batch_size: usize,
+    formats: Vec<&'static str>,
+    languages: usize,
+    default_language: Option<String>,
+    rate_limit_per_hour: u32,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Health {
+    ready: bool,
+    languages: usize,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Version {
+    version: &'static str,
+    git_sha: &'static str,
+    // Unix seconds, from build.rs
+    build_timestamp: u64,
+}
+
+#[derive(Clone)]
+struct Speaker {
+    id: VoiceId,
+    engines: Vec<Engine>,
+    gender: Option<Gender>,
+    // Every variant it speaks that shares the generic language it's listed under
+    language_codes: Vec<LanguageCode>,
+}
+
+impl Speaker {
+    fn supports(&self, engine: &Engine) -> bool {
+        self.engines.contains(engine)
+    }
+
+    // Neural sounds noticeably better, so use it whenever the voice has it. Generative comes last since it
+    // doesn't take phonemes everywhere, but a voice with only that still has to get something it supports.
+    fn best_engine(&self) -> Engine {
+        [Engine::Neural, Engine::Standard, Engine::from("generative")].into_iter()
+            .find(|engine| self.supports(engine))
+            // load_speakers never keeps a voice without an engine
+            .unwrap_or_else(|| self.engines[0].clone())
+    }
+}
+
+struct SpeechResponse {
+    // None when the client's If-None-Match already has it
+    audio: Option<Bytes>,
+    etag: String,
+    content_type: ContentType,
+    content_disposition: Header<'static>,
     language: String,
+    language_code: LanguageCode,
+    voice: VoiceId,
+    engine: Engine,
+    ipa_length: usize,
+    // Only when asked for with include_duration
+    duration_ms: Option<u64>,
+    // Only when asked for with strict, see ipa::unexpected_symbols
+    warnings: Option<String>,
+    // The requested voice wasn't loaded, so another one spoke
+    voice_fallback: bool,
+}
+
+impl<'r> Responder<'r, 'static> for SpeechResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        request.local_cache(|| Some(logging::SynthesisDetails {
+            language: self.language,
+            voice: self.voice.as_str().to_string(),
+            engine: self.engine.as_str().to_string(),
+            ipa_length: self.ipa_length,
+        }));
+
+        let mut response = Response::build();
+        // The format can come from Accept, so caches have to keep those apart
+        response.raw_header("Vary", "Accept");
+        if let Some(max_age) = request.rocket().state::<Settings>().map(|settings| settings.cache_max_age_secs).filter(|max_age| *max_age > 0) {
+            response.header(Header::new("Cache-Control", format!("public, max-age={max_age}")));
+        }
+        response
+            .header(Header::new("ETag", self.etag.clone()))
+            .header(Header::new("X-IPA-Voice", self.voice.as_str().to_string()))
+            .header(Header::new("X-IPA-Language-Code", self.language_code.as_str().to_string()));
+        if let Some(duration_ms) = self.duration_ms {
+            response.header(Header::new("X-Audio-Duration-Ms", duration_ms.to_string()));
+        }
+        if let Some(warnings) = self.warnings {
+            response.header(Header::new("X-IPA-Warnings", warnings));
+        }
+        if self.voice_fallback {
+            response.raw_header("X-IPA-Voice-Fallback", "true");
+        }
+        let Some(audio) = self.audio else {
+            return response.status(Status::NotModified).ok();
+        };
+
+        response
+            .header(self.content_type)
+            .header(self.content_disposition)
+            .raw_header("Accept-Ranges", "bytes");
+        // For seeking in <audio>. If-Range means only if the client's partial copy has the same ETag.
+        let range = request.headers().get_one("Range")
+            .filter(|_| request.method() == Method::Get)
+            .filter(|_| request.headers().get_one("If-Range").is_none_or(|if_range| if_range == self.etag))
+            .and_then(|range| range::parse(range, audio.len()));
+        match range {
+            Some(ByteRange::Satisfiable { start, end }) => response
+                .status(Status::PartialContent)
+                .header(Header::new("Content-Range", format!("bytes {start}-{end}/{}", audio.len())))
+                .sized_body(end - start + 1, Cursor::new(audio.slice(start..=end)))
+                .ok(),
+            Some(ByteRange::Unsatisfiable) => response
+                .status(Status::RangeNotSatisfiable)
+                .header(Header::new("Content-Range", format!("bytes */{}", audio.len())))
+                .ok(),
+            None => response.sized_body(audio.len(), Cursor::new(audio)).ok(),
+        }
+    }
+}
+
+struct SpeechStream {
+    audio: AudioStream,
+    content_type: ContentType,
+    content_disposition: Header<'static>,
+    language_code: LanguageCode,
+    warnings: Option<String>,
+    voice_fallback: bool,
+    details: logging::SynthesisDetails,
+}
+
+impl<'r> Responder<'r, 'static> for SpeechStream {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let voice = self.details.voice.clone();
+        request.local_cache(|| Some(self.details));
+
+        let mut response = Response::build();
+        if let Some(warnings) = self.warnings {
+            response.header(Header::new("X-IPA-Warnings", warnings));
+        }
+        if self.voice_fallback {
+            response.raw_header("X-IPA-Voice-Fallback", "true");
+        }
+        // No length up front means chunked, and small chunks get the first audio out sooner
+        response
+            .header(self.content_type)
+            .header(self.content_disposition)
+            .header(Header::new("X-IPA-Voice", voice))
+            .header(Header::new("X-IPA-Language-Code", self.language_code.as_str().to_string()))
+            .streamed_body(self.audio)
+            .max_chunk_size(STREAM_CHUNK_SIZE)
+            .ok()
+    }
+}
+
+struct IfNoneMatch(Option<String>);
+
+impl IfNoneMatch {
+    // Weak comparison, which is what RFC 9110 asks for with If-None-Match
+    fn matches(&self, etag: &str) -> bool {
+        self.0.as_deref().is_some_and(|header| header.split(',')
+            .map(|candidate| candidate.trim())
+            .any(|candidate| candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(IfNoneMatch(request.headers().get_one("If-None-Match").map(str::to_string)))
+    }
+}
+
+// What the Accept header asks for, only consulted when the body doesn't name a format
+enum AcceptFormat {
+    Any,
+    Format(&'static str),
+    // The header as sent and the types on offer, for the error
+    Unacceptable(String, String),
+}
+
+impl AcceptFormat {
+    fn apply(self, data: &mut RequestData) -> Result<(), ApiError> {
+        if data.format.is_some() {
+            return Ok(());
+        }
+
+        match self {
+            AcceptFormat::Any => Ok(()),
+            AcceptFormat::Format(format_name) => {
+                data.format = Some(format_name.to_string());
+                Ok(())
+            }
+            AcceptFormat::Unacceptable(accept, available) => Err(ApiError::new(Status::NotAcceptable, "not_acceptable", format!("Can't produce any of {accept}. Use one of {available}"))),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptFormat {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(accept) = request.accept() else {
+            return request::Outcome::Success(AcceptFormat::Any);
+        };
+        let offered: Vec<(&'static str, &ContentType)> = FORMAT_NAMES.iter()
+            .filter(|format_name| request.rocket().state::<Settings>().is_none_or(|settings| settings.allows_format(format_name)))
+            .map(|format_name| (*format_name, &FORMAT_TO_OUTPUT[format_name].1))
+            .collect();
+
+        // Most preferred first, and q=0 means never
+        let mut media_types: Vec<_> = accept.iter().filter(|media_type| media_type.weight_or(1.0) > 0.0).collect();
+        media_types.sort_by(|a, b| b.weight_or(1.0).total_cmp(&a.weight_or(1.0)));
+
+        for media_type in media_types.iter().map(|media_type| media_type.media_type()) {
+            if media_type.top() == "*" || (media_type.top() == "audio" && media_type.sub() == "*") {
+                return request::Outcome::Success(AcceptFormat::Any);
+            }
+            let format = offered.iter()
+                .find(|(_, content_type)| content_type.top() == media_type.top() && content_type.sub() == media_type.sub());
+            if let Some((format_name, _)) = format {
+                return request::Outcome::Success(AcceptFormat::Format(format_name));
+            }
+        }
+
+        let available = offered.iter().map(|(_, content_type)| content_type.media_type().to_string()).collect::<Vec<_>>().join(", ");
+        request::Outcome::Success(AcceptFormat::Unacceptable(accept.to_string(), available))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Preview {
+    audio: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct WarmSummary {
+    synthesized: usize,
+    failed: Vec<WarmFailure>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct WarmFailure {
+    // Position in the request array
+    index: usize,
+    error: ApiError,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde", untagged)]
+enum BatchResult {
+    Audio { content_type: String, audio: String },
+    Error { error: ApiError },
+}
+
+// Everything that changes what Polly sends back
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct SynthesisKey {
+    // SSML unless text_type is plain text
+    text: String,
+    text_type: TextType,
+    voice: VoiceId,
+    engine: Engine,
+    output_format: OutputFormat,
+    sample_rate: Option<String>,
+    speech_marks: Vec<SpeechMarkType>,
+    lexicon_names: Vec<String>,
+    // Only set for a variant, which bilingual voices need to know which language to speak
+    language_code: Option<LanguageCode>,
+}
+
+impl SynthesisKey {
+    // Has to come out the same on every instance sharing a bucket, so no std Hasher
+    fn digest(&self) -> String {
+        let speech_marks: Vec<&str> = self.speech_marks.iter().map(|speech_mark| speech_mark.as_str()).collect();
+        let key = [self.text.as_str(), self.text_type.as_str(), self.voice.as_str(), self.engine.as_str(), self.output_format.as_str(), self.sample_rate.as_deref().unwrap_or(""), &speech_marks.join(","), &self.lexicon_names.join(","), self.language_code.as_ref().map_or("", |language_code| language_code.as_str())].join("\0");
+        let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+
+        digest.as_ref().iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    fn object_key(&self) -> String {
+        format!("{}.{}", self.digest(), self.output_format.as_str())
+    }
+
+    // Same inputs always get the same audio back, which makes this a strong validator
+    fn etag(&self) -> String {
+        format!("\"{}\"", self.digest())
+    }
 }
 
 struct Polly {
-    client: Client,
-    speakers: HashMap<String, Vec<VoiceId>>,
+    synthesizer: Box<dyn SpeechSynthesizer>,
+    // Swapped out whole whenever voices (re)load, so readers never hold the lock for long
+    speakers: Arc<RwLock<Arc<Speakers>>>,
+    cache: Mutex<LruCache<SynthesisKey, Bytes>>,
+    shared_cache: Option<S3Cache>,
+    // So a burst of the same uncached request only calls Polly once
+    in_flight: SingleFlight<SynthesisKey, Result<Bytes, ApiError>>,
+    round_robin: RoundRobin,
+    // Covers every retry and reading the whole clip, not just the first response
+    timeout: Duration,
+    // Bounds calls to Polly in flight, so a spike doesn't run into the account's TPS limit
+    permits: Semaphore,
+    // How long a request waits for a permit before it's turned away
+    permit_wait: Duration,
+    circuit_breaker: CircuitBreaker,
+    max_audio_bytes: usize,
+    // Only false while IPA_SELFTEST is on and hasn't passed yet, see keep_self_testing
+    self_test_passed: Arc<AtomicBool>,
 }
 
+impl Polly {
+    fn speakers(&self) -> Arc<Speakers> {
+        self.speakers.read().unwrap().clone()
+    }
+
+    async fn synthesize_speech(&self, key: SynthesisKey, metrics: &Metrics) -> Result<Bytes, ApiError> {
+        let cached = self.cache.lock().unwrap().get(&key);
+        metrics.record_cache_lookup("memory", cached.is_some());
+        if let Some(audio) = cached {
+            return Ok(audio);
+        }
+
+        self.in_flight.run(key.clone(), self.fetch_speech(key, metrics)).await
+    }
+
+    // Past the memory cache
+    async fn fetch_speech(&self, key: SynthesisKey, metrics: &Metrics) -> Result<Bytes, ApiError> {
+        let object_key = key.object_key();
+        if let Some(shared_cache) = &self.shared_cache {
+            let shared = shared_cache.get(&object_key).await;
+            metrics.record_cache_lookup("s3", shared.is_some());
+            if let Some(audio) = shared {
+                self.cache_put(key, audio.clone(), metrics);
+                return Ok(audio);
+            }
+        }
+
+        self.circuit_breaker.check()?;
+        let _permit = self.acquire_permit().await?;
+        let started = Instant::now();
+        let result = rocket::tokio::time::timeout(self.timeout, self.read_limited(&key))
+            .instrument(tracing::info_span!("polly.synthesize_speech", voice = key.voice.as_str(), engine = key.engine.as_str()))
+            .await;
+        metrics.record_polly_latency(key.engine.as_str(), started.elapsed());
+
+        let audio = result.unwrap_or_else(|_| Err(self.synthesis_timeout()));
+        self.circuit_breaker.record(&audio);
+        let audio = audio?;
+
+        if let Some(shared_cache) = &self.shared_cache {
+            shared_cache.put(object_key, audio.clone());
+        }
+        self.cache_put(key, audio.clone(), metrics);
+        Ok(audio)
+    }
+
+    fn cache_put(&self, key: SynthesisKey, audio: Bytes, metrics: &Metrics) {
+        let mut cache = self.cache.lock().unwrap();
+        let evicted = cache.put(key, audio);
+        metrics.record_cache_put(evicted, cache.len());
+    }
+
+    // Only waits for Polly to start sending, the rest is up to whoever reads the stream
+    async fn stream_speech(&self, key: &SynthesisKey, metrics: &Metrics) -> Result<AudioStream, ApiError> {
+        self.circuit_breaker.check()?;
+        let _permit = self.acquire_permit().await?;
+        let started = Instant::now();
+        let result = rocket::tokio::time::timeout(self.timeout, self.synthesizer.stream(key)).await;
+        metrics.record_polly_latency(key.engine.as_str(), started.elapsed());
+
+        let audio = result.unwrap_or_else(|_| Err(self.synthesis_timeout()));
+        self.circuit_breaker.record(&audio);
+        // Too late for a 413 by the time it trips, so the client just gets a cut off stream
+        Ok(Box::pin(LimitedReader::new(audio?, self.max_audio_bytes)))
+    }
+
+    async fn read_limited(&self, key: &SynthesisKey) -> Result<Bytes, ApiError> {
+        let mut audio = Vec::new();
+        let stream = self.synthesizer.stream(key).await?;
+        match LimitedReader::new(stream, self.max_audio_bytes).read_to_end(&mut audio).await {
+            Ok(_) => Ok(Bytes::from(audio)),
+            Err(err) if synthesizer::is_too_large(&err) => {
+                warn!("Synthesized audio for {} went over {} bytes, giving up on it", key.voice.as_str(), self.max_audio_bytes);
+                Err(ApiError::new(Status::PayloadTooLarge, "audio_too_large", format!("The audio would be over {} bytes. Try shorter IPA or another format", self.max_audio_bytes)))
+            }
+            Err(err) => Err(synthesis_failed(&err)),
+        }
+    }
+
+    // Waits a little for a slot to free up before telling the client to back off
+    async fn acquire_permit(&self) -> Result<SemaphorePermit<'_>, ApiError> {
+        match rocket::tokio::time::timeout(self.permit_wait, self.permits.acquire()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => {
+                warn!("Too many syntheses in flight, turning a request away");
+                Err(ApiError::new(Status::ServiceUnavailable, "overloaded", "Too many speech requests at once, try again shortly".to_string()).with_retry_after(1))
+            }
+        }
+    }
+
+    fn synthesis_timeout(&self) -> ApiError {
+        error!("Synthesis timed out after {}s", self.timeout.as_secs());
+        ApiError::new(Status::GatewayTimeout, "synthesis_timeout", "Timed out waiting for speech to be synthesized".to_string())
+    }
+}
+
+fn synthesis_failed(err: &dyn std::fmt::Display) -> ApiError {
+    error!("Failed to synthesize speech: {err}");
+    ApiError::new(Status::BadGateway, "synthesis_failed", "Failed to synthesize speech".to_string())
+}
+
+// Every guard is an argument, which adds up
 #[post("/", format = "json", data = "<validated_data>")]
-async fn speak(validated_data: Validated<Json<RequestData>>, polly: &State<Polly>, _limitguard: RocketGovernor<'_, RateLimitGuard>) -> Result<ReaderStream![impl rocket::tokio::io::AsyncRead], status::BadRequest<String>> {
+#[allow(clippy::too_many_arguments)]
+async fn speak(validated_data: Validated<Json<RequestData>>, accept_format: AcceptFormat, if_none_match: IfNoneMatch, polly: &State<Polly>, languages: Languages, settings: &State<Settings>, metrics: &State<Metrics>, _not_denied: NotDenied, _api_key: ApiKey, _rate_limit: RateLimit) -> Result<SpeechResponse, ApiError> {
+    let mut data = validated_data.into_inner();
+    accept_format.apply(&mut data)?;
+
+    synthesize(&data, &if_none_match, polly, &languages, settings, metrics).await
+}
+
+// Same as POSTing to / but usable as an <audio> src
+#[get("/speak?<data..>")]
+#[allow(clippy::too_many_arguments)]
+async fn speak_get(data: Result<RequestData, Errors<'_>>, accept_format: AcceptFormat, if_none_match: IfNoneMatch, polly: &State<Polly>, languages: Languages, settings: &State<Settings>, metrics: &State<Metrics>, _not_denied: NotDenied, _api_key: ApiKey, _rate_limit: RateLimit) -> Result<SpeechResponse, ApiError> {
+    let mut data = data.map_err(|errors| ApiError::bad_request("bad_request", errors.to_string()))?;
+    data.validate().map_err(|errors| ApiError::from_validation_errors(&errors))?;
+    accept_format.apply(&mut data)?;
+
+    synthesize(&data, &if_none_match, polly, &languages, settings, metrics).await
+}
+
+// The audio inline as a data: URI, handy for single words where a second request isn't worth it
+#[get("/speak/preview?<data..>")]
+#[allow(clippy::too_many_arguments)]
+async fn speak_preview(data: Result<RequestData, Errors<'_>>, polly: &State<Polly>, languages: Languages, settings: &State<Settings>, metrics: &State<Metrics>, _not_denied: NotDenied, _api_key: ApiKey, _rate_limit: RateLimit) -> Result<Json<Preview>, ApiError> {
+    let data = data.map_err(|errors| ApiError::bad_request("bad_request", errors.to_string()))?;
+    data.validate().map_err(|errors| ApiError::from_validation_errors(&errors))?;
+
+    let speech = synthesize(&data, &IfNoneMatch(None), polly, &languages, settings, metrics).await?;
+    let audio = speech.audio.unwrap_or_default();
+    if audio.len() > MAX_PREVIEW_BYTES {
+        return Err(ApiError::new(Status::PayloadTooLarge, "preview_too_large", format!("The audio is {} bytes, previews can be at most {MAX_PREVIEW_BYTES}. Use GET /speak instead", audio.len())));
+    }
+
+    Ok(Json(Preview {
+        audio: format!("data:{};base64,{}", speech.content_type, base64::encode(&audio)),
+    }))
+}
+
+// Skips the caches and hands Polly's stream straight over, so playback can start before the whole clip exists
+#[post("/speak/stream", format = "json", data = "<validated_data>")]
+#[allow(clippy::too_many_arguments)]
+async fn speak_stream(validated_data: Validated<Json<RequestData>>, polly: &State<Polly>, languages: Languages, settings: &State<Settings>, metrics: &State<Metrics>, _not_denied: NotDenied, _api_key: ApiKey, _rate_limit: RateLimit) -> Result<SpeechStream, ApiError> {
+    recorded(metrics, stream_speech(&validated_data.into_inner(), polly, &languages, settings, metrics)).await
+}
+
+// Timings (visemes, words, ...) for the same speech instead of the audio, for lip sync or highlighting
+#[post("/speak/marks", format = "json", data = "<validated_data>")]
+#[allow(clippy::too_many_arguments)]
+async fn speak_marks(validated_data: Validated<Json<RequestData>>, polly: &State<Polly>, languages: Languages, settings: &State<Settings>, metrics: &State<Metrics>, _not_denied: NotDenied, _api_key: ApiKey, _rate_limit: RateLimit) -> Result<Json<Vec<Value>>, ApiError> {
+    recorded(metrics, speech_marks(&validated_data.into_inner(), polly, &languages, settings, metrics)).await
+}
+
+// Everything short of calling Polly, for checking what a request turns into.
+// Costs nothing, so it isn't rate limited.
+#[post("/speak/ssml", format = "json", data = "<validated_data>")]
+fn speak_ssml(validated_data: Validated<Json<RequestData>>, polly: &State<Polly>, languages: Languages, settings: &State<Settings>, _not_denied: NotDenied, _api_key: ApiKey) -> Result<(ContentType, String), ApiError> {
     let data = validated_data.into_inner();
-    let target_language = &*data.language;
-    if !LANGUAGE_TO_CODE.contains_key(target_language) {
-        return Err(status::BadRequest(Some(format!("Language {target_language} is unsupported"))));
+    let resolved = resolve(&data, polly, &languages, settings)?;
+    Ok((ContentType::Plain, resolved.key.text))
+}
+
+// Counts as a single request against the rate limit, which is why batches are capped
+#[post("/speak/batch", format = "json", data = "<batch>")]
+#[allow(clippy::too_many_arguments)]
+async fn speak_batch(batch: Json<Vec<RequestData>>, polly: &State<Polly>, languages: Languages, settings: &State<Settings>, metrics: &State<Metrics>, _not_denied: NotDenied, _api_key: ApiKey, _rate_limit: RateLimit) -> Result<Json<Vec<BatchResult>>, ApiError> {
+    if batch.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::bad_request("batch_too_large", format!("Batches can contain at most {MAX_BATCH_SIZE} items, got {}", batch.len())));
+    }
+
+    let languages = &*languages;
+    let results = futures::future::join_all(batch.iter().map(|data| async move {
+        data.validate().map_err(|errors| ApiError::from_validation_errors(&errors))?;
+        synthesize(data, &IfNoneMatch(None), polly, languages, settings, metrics).await
+    })).await;
+
+    Ok(Json(results.into_iter()
+        .map(|result| match result {
+            Ok(speech) => BatchResult::Audio {
+                content_type: speech.content_type.to_string(),
+                audio: base64::encode(speech.audio.unwrap_or_default()),
+            },
+            Err(error) => BatchResult::Error { error },
+        })
+        .collect()))
+}
+
+// Synthesizes into the caches ahead of time. Counts against the caller's rate limit and is capped
+// like /speak/batch, and since it fills the caches everyone shares it also needs API keys turned on.
+#[post("/cache/warm", format = "json", data = "<batch>")]
+#[allow(clippy::too_many_arguments)]
+async fn cache_warm(batch: Json<Vec<RequestData>>, polly: &State<Polly>, languages: Languages, settings: &State<Settings>, metrics: &State<Metrics>, _not_denied: NotDenied, api_key: ApiKey, _rate_limit: RateLimit) -> Result<Json<WarmSummary>, ApiError> {
+    if api_key.0.is_none() {
+        return Err(ApiError::new(Status::Forbidden, "api_keys_disabled", "Cache warming is only available when IPA_API_KEYS is set".to_string()));
+    }
+    if batch.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::bad_request("batch_too_large", format!("Batches can contain at most {MAX_BATCH_SIZE} items, got {}", batch.len())));
+    }
+
+    let languages = &*languages;
+    let results = futures::future::join_all(batch.iter().map(|data| async move {
+        data.validate().map_err(|errors| ApiError::from_validation_errors(&errors))?;
+        synthesize(data, &IfNoneMatch(None), polly, languages, settings, metrics).await
+    })).await;
+
+    let mut summary = WarmSummary { synthesized: 0, failed: Vec::new() };
+    for (index, result) in results.into_iter().enumerate() {
+        match result {
+            Ok(_) => summary.synthesized += 1,
+            Err(error) => summary.failed.push(WarmFailure { index, error }),
+        }
+    }
+
+    Ok(Json(summary))
+}
+
+// Everything the speak routes do once the request itself is known to be well-formed
+async fn synthesize(data: &RequestData, if_none_match: &IfNoneMatch, polly: &Polly, languages: &LanguageMap, settings: &Settings, metrics: &Metrics) -> Result<SpeechResponse, ApiError> {
+    // Filled in as they become known, the language with its canonical name once it's been looked up
+    let span = tracing::info_span!("speak", language = data.language.as_deref().unwrap_or_default(), voice = field::Empty, engine = field::Empty, ipa_length = field::Empty, outcome = field::Empty);
+    let result = recorded(metrics, resolve_and_synthesize(data, if_none_match, polly, languages, settings, metrics))
+        .instrument(span.clone())
+        .await;
+
+    match &result {
+        Ok(speech) => {
+            span.record("language", speech.language.as_str());
+            span.record("voice", speech.voice.as_str());
+            span.record("engine", speech.engine.as_str());
+            span.record("ipa_length", speech.ipa_length);
+            span.record("outcome", "success");
+        }
+        Err(error) => {
+            span.record("outcome", error.code());
+        }
+    }
+
+    result
+}
+
+async fn recorded<T>(metrics: &Metrics, synthesis: impl std::future::Future<Output = Result<T, ApiError>>) -> Result<T, ApiError> {
+    metrics.record_request();
+    let result = synthesis.await;
+    if let Err(error) = &result {
+        metrics.record_failure(error.code());
+    }
+
+    result
+}
+
+async fn resolve_and_synthesize(data: &RequestData, if_none_match: &IfNoneMatch, polly: &Polly, languages: &LanguageMap, settings: &Settings, metrics: &Metrics) -> Result<SpeechResponse, ApiError> {
+    let resolved = resolve(data, polly, languages, settings)?;
+    let (voice, engine) = (resolved.key.voice.clone(), resolved.key.engine.clone());
+
+    // Known before synthesizing, so a client that already has the clip doesn't cost a Polly call
+    let etag = resolved.key.etag();
+    let (output_format, sample_rate) = (resolved.key.output_format.clone(), resolved.key.sample_rate.clone());
+    let audio = if if_none_match.matches(&etag) {
+        None
+    } else {
+        Some(polly.synthesize_speech(resolved.key, metrics).await?)
+    };
+    let duration_ms = match &audio {
+        Some(audio) if data.include_duration == Some(true) => duration::duration_ms(audio, &output_format, sample_rate.as_deref()),
+        _ => None,
+    };
+    metrics.record_success(resolved.language_name, engine.as_str());
+
+    Ok(SpeechResponse {
+        audio,
+        etag,
+        content_type: resolved.content_type,
+        content_disposition: content_disposition(resolved.language_name, resolved.ipa, resolved.format_name),
+        language: resolved.language_name.to_string(),
+        language_code: resolved.language_code,
+        voice,
+        engine,
+        ipa_length: resolved.ipa.chars().count(),
+        duration_ms,
+        warnings: resolved.warnings,
+        voice_fallback: resolved.voice_fallback,
+    })
+}
+
+// Everything about a request that gets settled before Polly is involved
+struct ResolvedRequest<'a> {
+    key: SynthesisKey,
+    // Trimmed
+    ipa: &'a str,
+    content_type: ContentType,
+    language_name: &'a str,
+    language_code: LanguageCode,
+    format_name: &'a str,
+    warnings: Option<String>,
+    voice_fallback: bool,
+}
+
+fn resolve<'a>(data: &'a RequestData, polly: &Polly, languages: &'a LanguageMap, settings: &Settings) -> Result<ResolvedRequest<'a>, ApiError> {
+    let target_language = match data.language.as_deref().filter(|language| !language.trim().is_empty()) {
+        Some(language) => language,
+        None => match languages.default_language() {
+            Some(language) => language,
+            None => return Err(ApiError::bad_request("language_missing", "language is required".to_string())),
+        },
+    };
+    // It ends up in error messages, so don't let anything odd or huge through to be echoed back
+    if target_language.chars().count() > MAX_LANGUAGE_LENGTH {
+        return Err(ApiError::bad_request("invalid_language", format!("Language can be at most {MAX_LANGUAGE_LENGTH} characters long")));
+    }
+    if !target_language.chars().all(|character| character.is_alphabetic() || matches!(character, ' ' | '-')) {
+        return Err(ApiError::bad_request("invalid_language", "Language can only contain letters, spaces and hyphens".to_string()));
+    }
+    let (language_name, language_code) = match languages.get(target_language) {
+        Some(language) => language,
+        None => {
+            let suggestion = languages.closest(target_language).map(|closest| format!(". Did you mean {closest}?")).unwrap_or_default();
+            // Not a malformed request, there's just nothing for that language
+            return Err(ApiError::new(Status::NotFound, "unsupported_language", format!("Language {target_language} is unsupported{suggestion}")));
+        }
+    };
+
+    // Plain text skips the phonemes, and the SSML with them
+    let plain_text = match data.text_type.as_deref().unwrap_or("ipa") {
+        "ipa" => false,
+        "text" => true,
+        text_type => return Err(ApiError::unsupported_value("unsupported_text_type", "text_type", text_type, &["ipa", "text"])),
+    };
+
+    // Both are what Polly calls them in the phoneme tag
+    let alphabet = data.alphabet.as_deref().unwrap_or("ipa");
+    if alphabet != "ipa" && alphabet != "x-sampa" {
+        return Err(ApiError::unsupported_value("unsupported_alphabet", "alphabet", alphabet, &["ipa", "x-sampa"]));
+    }
+
+    // Stray spaces around it would end up inside the phoneme
+    let ipa = data.ipa.trim();
+    // X-SAMPA uses the ASCII characters this would replace
+    let normalized_ipa;
+    let mut words: Vec<&str> = Vec::new();
+    let mut warnings = None;
+    if plain_text {
+        if ipa.chars().count() > MAX_TEXT_LENGTH {
+            return Err(ApiError::bad_request("ipa_too_long", format!("Text can be at most {MAX_TEXT_LENGTH} characters long")));
+        }
+        if ipa.is_empty() {
+            return Err(ApiError::bad_request("ipa_empty", "Text is empty or only whitespace".to_string()));
+        }
+        // These only exist as SSML tags
+        if data.rate.is_some() || data.pitch.is_some() || data.volume.is_some() || data.break_ms.is_some() || data.effect.is_some() || data.carrier == Some(true) || data.carrier_template.is_some() {
+            return Err(ApiError::bad_request("unsupported_with_text", "rate, pitch, volume, break_ms, effect and carriers need SSML, so they can't be used with text_type text".to_string()));
+        }
+    } else {
+        let phonemes = if data.normalize == Some(true) && alphabet == "ipa" {
+            normalized_ipa = ipa::normalize(ipa);
+            normalized_ipa.as_str()
+        } else {
+            ipa
+        };
+        // Several words can be sent at once separated by |, and each one gets its own phoneme
+        words = phonemes.split(WORD_SEPARATOR).map(str::trim).filter(|word| !word.is_empty()).collect();
+        let max_ipa_length = languages.max_ipa_length(language_name);
+        if words.iter().any(|word| word.chars().count() as u64 > max_ipa_length) {
+            return Err(ApiError::bad_request("ipa_too_long", format!("Each IPA word for {language_name} can be at most {max_ipa_length} characters long")));
+        }
+
+        if words.is_empty() {
+            return Err(ApiError::bad_request("ipa_empty", "IPA is empty or only whitespace".to_string()));
+        }
+        if let Some(word) = words.iter().find(|word| !ipa::has_sounds(word)) {
+            return Err(ApiError::bad_request("ipa_without_sounds", format!("IPA {word} has no sounds in it, only punctuation")));
+        }
+
+        // Only a hint, the IPA still gets spoken
+        if data.strict == Some(true) && alphabet == "ipa" {
+            if let Some(unexpected) = ipa::unexpected_symbols(language_name, phonemes).filter(|unexpected| !unexpected.is_empty()) {
+                // Code points, header values can't be trusted with anything but ASCII
+                let symbols = unexpected.iter().map(|character| format!("U+{:04X}", u32::from(*character))).collect::<Vec<_>>().join(", ");
+                let verb = if unexpected.len() == 1 { "isn't" } else { "aren't" };
+                warnings = Some(format!("{symbols} {verb} usually in {language_name}"));
+            }
+        }
+
+        // X-SAMPA is plain ASCII, so there's nothing to check it against
+        if settings.validate_characters && alphabet == "ipa" {
+            if let Err(invalid_characters) = ipa::validate_ipa(phonemes) {
+                let invalid_characters: String = invalid_characters.iter().map(|character| format!("'{character}' ")).collect();
+                return Err(ApiError::bad_request("invalid_characters", format!("IPA contains characters that aren't IPA: {}", invalid_characters.trim_end())));
+            }
+        }
+    }
+
+    let prosody = ssml::Prosody {
+        rate: data.rate.as_deref(),
+        pitch: data.pitch.as_deref(),
+        volume: data.volume.as_deref(),
+    };
+    if let Err((attribute, value)) = prosody.validate() {
+        return Err(ApiError::bad_request("invalid_prosody", format!("{value} is not a valid {attribute}")));
+    }
+
+    let effect = data.effect.as_deref();
+    if let Some(effect) = effect.filter(|effect| !ssml::EFFECT_NAMES.contains(effect)) {
+        return Err(ApiError::unsupported_value("unsupported_effect", "effect", effect, &ssml::EFFECT_NAMES));
+    }
+
+    let carrier = match data.carrier_template.as_deref() {
+        Some(template) if template.chars().count() > MAX_CARRIER_TEMPLATE_LENGTH => {
+            return Err(ApiError::bad_request("invalid_carrier_template", format!("Carrier template can be at most {MAX_CARRIER_TEMPLATE_LENGTH} characters long")));
+        }
+        Some(template) if template.matches("{}").count() != 1 => {
+            return Err(ApiError::bad_request("invalid_carrier_template", "Carrier template needs exactly one {} for where the IPA goes".to_string()));
+        }
+        Some(template) => Some(template),
+        None if data.carrier == Some(true) => match languages.carrier_template(language_name) {
+            Some(template) => Some(template),
+            None => return Err(ApiError::bad_request("no_carrier_template", format!("There's no carrier sentence for {language_name}, pass carrier_template instead"))),
+        },
+        None => None,
+    };
+
+    let lexicon_names: Vec<String> = data.lexicon_names.as_deref()
+        .map(|lexicon_names| lexicon_names.split(',').map(str::trim).filter(|name| !name.is_empty()).map(String::from).collect())
+        .unwrap_or_default();
+    if lexicon_names.len() > MAX_LEXICONS {
+        return Err(ApiError::bad_request("too_many_lexicons", format!("At most {MAX_LEXICONS} lexicons can be applied at once")));
+    }
+    // Polly's own naming rule, checking it here saves a round trip for obvious typos
+    if let Some(name) = lexicon_names.iter().find(|name| name.len() > MAX_LEXICON_NAME_LENGTH || !name.chars().all(|character| character.is_ascii_alphanumeric())) {
+        return Err(ApiError::bad_request("invalid_lexicon_name", format!("Lexicon name {name} is invalid. Names are up to {MAX_LEXICON_NAME_LENGTH} letters and digits")));
     }
 
-    let mut rng = rand::rngs::StdRng::from_entropy();
+    let target_format = data.format.as_deref().unwrap_or_else(|| settings.default_format());
+    let (output_format, content_type) = match FORMAT_TO_OUTPUT.get(target_format) {
+        Some(format) if settings.allows_format(target_format) => format.clone(),
+        _ => return Err(ApiError::unsupported_value("unsupported_format", "format", target_format, settings.allowed_formats())),
+    };
+
+    let requested_gender = match data.gender.as_deref() {
+        Some(gender_name) => match [Gender::Female, Gender::Male].into_iter().find(|gender| gender.as_str().eq_ignore_ascii_case(gender_name)) {
+            Some(gender) => Some(gender),
+            None => return Err(ApiError::unsupported_value("unsupported_gender", "gender", gender_name, &["Female", "Male"])),
+        },
+        None => None,
+    };
 
-    let generic_language = &*generic_language_from_code(LANGUAGE_TO_CODE.get(target_language).unwrap().clone());
-    if !polly.speakers.contains_key(generic_language) {
-        return Err(status::BadRequest(Some(format!("Language {target_language} is unsupported"))));
+    let requested_engine = match data.engine.as_deref() {
+        Some(engine_name) => match NAME_TO_ENGINE.get(engine_name) {
+            Some(engine) => Some(engine.clone()),
+            None => return Err(ApiError::unsupported_value("unsupported_engine", "engine", engine_name, &ENGINE_NAMES)),
+        },
+        None => None,
+    };
+
+    let default_engine = languages.default_engine(language_name);
+
+    let strategy = match data.strategy.as_deref() {
+        Some(strategy_name) => match strategy_name.parse() {
+            Ok(strategy) => strategy,
+            Err(()) => return Err(ApiError::unsupported_value("unsupported_strategy", "strategy", strategy_name, &voice_selection::STRATEGY_NAMES)),
+        },
+        None => settings.voice_strategy,
+    };
+
+    let speakers = polly.speakers();
+    // Nothing loaded at all is our fault, not the client's
+    if speakers.is_empty() {
+        return Err(ApiError::new(Status::ServiceUnavailable, "no_speakers_loaded", "No voices are loaded for any language. Either Polly hasn't been reached yet or none of its voices support the configured engines".to_string()).with_retry_after(VOICE_RETRY_SECS));
     }
+    let Some(language_speakers) = generic_language_from_code(language_code).and_then(|generic_language| speakers.get(&generic_language)) else {
+        return Err(ApiError::bad_request("no_speakers", format!("{language_name} is supported, but no voices are loaded for it")));
+    };
 
-    let random_speaker = polly.speakers.get(generic_language).unwrap().choose(&mut rng).unwrap();
-    let ssml_text = format!("<phoneme alphabet='ipa' ph='{}'></phoneme>", data.ipa);
+    // A variant like en-GB narrows it down to voices with that accent
+    let variant = match data.variant.as_deref() {
+        Some(variant_code) => match LanguageCode::from(variant_code) {
+            LanguageCode::Unknown(_) => return Err(ApiError::bad_request("unsupported_variant", format!("Variant {variant_code} isn't a language code Polly knows, e.g. en-GB"))),
+            variant => Some(variant),
+        },
+        None => None,
+    };
+    let language_speakers: Vec<&Speaker> = language_speakers.iter()
+        .filter(|speaker| variant.as_ref().is_none_or(|variant| speaker.language_codes.contains(variant)))
+        .collect();
+    if let Some(variant) = &variant {
+        if language_speakers.is_empty() {
+            return Err(ApiError::bad_request("unavailable_variant", format!("No {language_name} voices speak {}", variant.as_str())));
+        }
+    }
+
+    let mut voice_fallback = false;
+    let requested_speaker = match data.voice.as_deref() {
+        Some(voice_name) => match language_speakers.iter().find(|speaker| speaker.id.as_str() == voice_name) {
+            Some(voice) if requested_gender.as_ref().is_some_and(|gender| voice.gender.as_ref() != Some(gender)) => {
+                return Err(ApiError::bad_request("unavailable_gender", format!("Voice {voice_name} is not {}", requested_gender.unwrap().as_str())));
+            }
+            // Polly would only turn it down once we'd asked, as a 502
+            Some(voice) if requested_engine.as_ref().is_some_and(|engine| !voice.supports(engine)) => {
+                let supported_engines = voice.engines.iter().map(|engine| engine.as_str()).collect::<Vec<_>>().join(", ");
+                return Err(ApiError::bad_request("unsupported_engine", format!("Voice {voice_name} doesn't support the {} engine. It supports {supported_engines}", requested_engine.unwrap().as_str())));
+            }
+            Some(voice) => Some(*voice),
+            None if data.strict_voice == Some(true) => {
+                let available_voices = language_speakers.iter().map(|speaker| speaker.id.as_str()).collect::<Vec<_>>().join(", ");
+                return Err(ApiError::bad_request("unavailable_voice", format!("Voice {voice_name} is unavailable for {language_name}. Available voices: {available_voices}")));
+            }
+            // Most likely a voice name cached from /voices that a refresh has since dropped
+            None => {
+                debug!("Voice {voice_name} isn't loaded for {language_name}, picking another");
+                voice_fallback = true;
+                None
+            }
+        },
+        None => None,
+    };
+    let speaker = match requested_speaker {
+        Some(speaker) => speaker,
+        None => {
+            let gendered_speakers: Vec<&Speaker> = language_speakers.iter().copied()
+                .filter(|speaker| requested_gender.is_none() || speaker.gender == requested_gender)
+                .collect();
+            if gendered_speakers.is_empty() {
+                return Err(ApiError::bad_request("unavailable_gender", format!("No {} voices available for {language_name}", requested_gender.unwrap().as_str())));
+            }
+
+            let candidates: Vec<&Speaker> = gendered_speakers.into_iter()
+                .filter(|speaker| requested_engine.as_ref().is_none_or(|engine| speaker.supports(engine)))
+                .collect();
+            // Unlike a requested engine, the default is only a preference
+            let preferred: Vec<&Speaker> = match default_engine {
+                Some(engine) => candidates.iter().copied().filter(|speaker| speaker.supports(engine)).collect(),
+                None => Vec::new(),
+            };
+            let mut candidates = if preferred.is_empty() { candidates } else { preferred };
+            // Sorted so the turns and the first one don't depend on the order Polly listed them in
+            if strategy != Strategy::Random {
+                candidates.sort_by_key(|speaker| speaker.id.as_str());
+            }
+            let chosen = match strategy {
+                // A seed makes the same request pick the same speaker every time
+                Strategy::Random => match data.seed {
+                    Some(seed) => candidates.choose(&mut rand::rngs::StdRng::seed_from_u64(seed)),
+                    None => candidates.choose(&mut rand::thread_rng()),
+                },
+                Strategy::RoundRobin => candidates.get(polly.round_robin.next(language_name, candidates.len())),
+                Strategy::First => candidates.first(),
+            };
+            match chosen {
+                Some(speaker) => *speaker,
+                None => {
+                    let engine_name = requested_engine.as_ref().unwrap().as_str();
+                    return Err(ApiError::bad_request("unsupported_engine", format!("No {language_name} voices support the {engine_name} engine")));
+                }
+            }
+        }
+    };
+    // The request, then IPA_DEFAULT_ENGINES, then whatever sounds best on the voice
+    let engine = requested_engine
+        .or_else(|| default_engine.filter(|engine| speaker.supports(engine)).cloned())
+        .unwrap_or_else(|| speaker.best_engine());
+    // Polly only whispers on the standard engine, so that wins over anything but an engine in the request
+    let engine = match effect {
+        Some("whispered") if engine != Engine::Standard => {
+            if data.engine.is_some() || !speaker.supports(&Engine::Standard) {
+                return Err(ApiError::bad_request("unsupported_effect", format!("Voice {} can only whisper on the standard engine", speaker.id.as_str())));
+            }
+            Engine::Standard
+        }
+        _ => engine,
+    };
 
-    let resp = polly.client
-        .synthesize_speech()
-        .output_format(OutputFormat::OggVorbis)
-        .text(ssml_text)
-        .text_type(TextType::Ssml)
-        .voice_id(random_speaker.clone())
-        .send()
-        .await
-        .expect("failed to synthesize speech");
+    if let Some(sample_rate) = data.sample_rate.as_deref() {
+        let allowed_rates = allowed_sample_rates(&output_format, &engine);
+        if !allowed_rates.contains(&sample_rate) {
+            return Err(ApiError::bad_request("unsupported_sample_rate", format!("Sample rate {sample_rate} is unsupported for {target_format} on the {} engine. Use one of {}", engine.as_str(), allowed_rates.join(", "))));
+        }
+    }
+    let language_code = variant.clone().unwrap_or_else(|| language_code.clone());
+    let (text, text_type) = if plain_text {
+        (ipa.to_string(), TextType::Text)
+    } else {
+        let ssml_text = ssml::build_ssml(&ssml::SsmlParams {
+            alphabet,
+            words: &words,
+            break_ms: data.break_ms,
+            language_code: Some(language_code.as_str()),
+            carrier,
+            effect,
+            prosody: &prosody,
+        });
+        (ssml_text, TextType::Ssml)
+    };
 
-    Ok(ReaderStream::one(resp.audio_stream.into_async_read()))
+    Ok(ResolvedRequest {
+        key: SynthesisKey {
+            text,
+            text_type,
+            voice: speaker.id.clone(),
+            engine,
+            output_format,
+            sample_rate: data.sample_rate.clone(),
+            speech_marks: Vec::new(),
+            lexicon_names,
+            language_code: variant.clone(),
+        },
+        ipa,
+        content_type,
+        language_name,
+        language_code,
+        format_name: target_format,
+        warnings,
+        voice_fallback,
+    })
 }
 
+async fn speech_marks(data: &RequestData, polly: &Polly, languages: &LanguageMap, settings: &Settings, metrics: &Metrics) -> Result<Json<Vec<Value>>, ApiError> {
+    let speech_marks = match data.speech_marks.as_deref() {
+        Some(mark_names) => mark_names.split(',')
+            .map(|mark_name| match NAME_TO_SPEECH_MARK.get(mark_name.trim()) {
+                Some(speech_mark) => Ok(speech_mark.clone()),
+                None => Err(ApiError::unsupported_value("unsupported_speech_mark", "speech_marks", mark_name.trim(), &SPEECH_MARK_NAMES)),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => vec![SpeechMarkType::Viseme],
+    };
+
+    let mut resolved = resolve(data, polly, languages, settings)?;
+    // Polly only does marks as JSON, and the sample rate means nothing to them
+    resolved.key.output_format = OutputFormat::Json;
+    resolved.key.sample_rate = None;
+    resolved.key.speech_marks = speech_marks;
+    let engine = resolved.key.engine.clone();
+
+    // One JSON object per line, which is awkward for clients, so hand them an array instead
+    let marks = polly.synthesize_speech(resolved.key, metrics).await?;
+    let marks = String::from_utf8_lossy(&marks).lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| rocket::serde::json::from_str(line).map_err(|err| synthesis_failed(&err)))
+        .collect::<Result<Vec<Value>, _>>()?;
+    metrics.record_success(resolved.language_name, engine.as_str());
+
+    Ok(Json(marks))
+}
+
+async fn stream_speech(data: &RequestData, polly: &Polly, languages: &LanguageMap, settings: &Settings, metrics: &Metrics) -> Result<SpeechStream, ApiError> {
+    let resolved = resolve(data, polly, languages, settings)?;
+    let audio = polly.stream_speech(&resolved.key, metrics).await?;
+    metrics.record_success(resolved.language_name, resolved.key.engine.as_str());
+
+    Ok(SpeechStream {
+        audio,
+        content_type: resolved.content_type,
+        content_disposition: content_disposition(resolved.language_name, resolved.ipa, resolved.format_name),
+        language_code: resolved.language_code,
+        warnings: resolved.warnings,
+        voice_fallback: resolved.voice_fallback,
+        details: logging::SynthesisDetails {
+            language: resolved.language_name.to_string(),
+            voice: resolved.key.voice.as_str().to_string(),
+            engine: resolved.key.engine.as_str().to_string(),
+            ipa_length: resolved.ipa.chars().count(),
+        },
+    })
+}
+
+#[get("/voices")]
+fn voices(polly: &State<Polly>) -> Json<HashMap<String, Vec<String>>> {
+    Json(polly.speakers().iter()
+        .map(|(generic_language, speakers)| (generic_language.clone(), speakers.iter().map(|speaker| speaker.id.as_str().to_string()).collect()))
+        .collect())
+}
+
+#[get("/rate-limit")]
+fn rate_limit_status(status: RateLimitStatus, _not_denied: NotDenied, _api_key: ApiKey) -> Json<RateLimitStatus> {
+    Json(status)
+}
+
+#[get("/languages")]
+fn languages(polly: &State<Polly>, language_map: Languages) -> Json<Vec<LanguageInfo>> {
+    let speakers = polly.speakers();
+    let mut languages: Vec<LanguageInfo> = language_map.iter()
+        .map(|(name, code)| LanguageInfo {
+            name: name.to_string(),
+            code: code.as_str().to_string(),
+            available: generic_language_from_code(code).is_some_and(|generic_language| speakers.contains_key(&generic_language)),
+        })
+        .collect();
+    languages.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Json(languages)
+}
+
+// Deliberately doesn't touch Polly, we only got this far if describe_voices worked
+#[get("/metrics")]
+fn metrics_endpoint(metrics: &State<Metrics>) -> (ContentType, String) {
+    (ContentType::new("text", "plain").with_params(("version", "0.0.4")), metrics.render())
+}
+
+#[get("/healthz")]
+fn healthz(polly: &State<Polly>) -> status::Custom<Json<Health>> {
+    let loaded_languages = polly.speakers().len();
+    let ready = loaded_languages > 0 && polly.self_test_passed.load(Ordering::Relaxed);
+    let status = if ready { Status::Ok } else { Status::ServiceUnavailable };
+
+    status::Custom(status, Json(Health { ready, languages: loaded_languages }))
+}
+
+// Built from the same constants and config the handlers check against, so it can't drift from them
+#[get("/help")]
+fn help(languages: Languages, rate_limits: &State<RateLimits>, settings: &State<Settings>) -> Json<Help> {
+    // Straight from RequestData so it can't fall out of step. language is only optional with a default to fall back on.
+    let mut required = vec!["ipa"];
+    if languages.default_language().is_none() {
+        required.push("language");
+    }
+    let optional: Vec<&'static str> = serde_fields::struct_fields::<RequestData>().iter().copied()
+        .filter(|field| !required.contains(field))
+        .collect();
+
+    Json(Help {
+        endpoint: "POST /",
+        required,
+        optional,
+        min_ipa_length: MIN_IPA_LENGTH,
+        max_ipa_length: MAX_IPA_LENGTH,
+        max_ipa_lengths: languages.max_ipa_lengths().clone(),
+        max_text_length: MAX_TEXT_LENGTH,
+        max_break_ms: ssml::MAX_BREAK_MS,
+        max_batch_size: MAX_BATCH_SIZE,
+        formats: settings.allowed_formats().to_vec(),
+        languages: languages.iter().count(),
+        default_language: languages.default_language().map(String::from),
+        rate_limit_per_hour: rate_limits.per_hour().get(),
+    })
+}
+
+#[get("/version")]
+fn version() -> Json<Version> {
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("IPA_GIT_SHA"),
+        build_timestamp: env!("IPA_BUILD_TIMESTAMP").parse().unwrap_or(0),
+    })
+}
+
+const PLAYGROUND_HTML: &str = include_str!("playground.html");
+
+// Only for an Accept that names text/html, curl's */* still gets the plain text so scripts keep working
 #[get("/")]
-fn index() -> &'static str {
-    "This is a ipa_server, running on Rocket (Rust). You probably meant to do a POST request"
+fn index(accept: Option<&Accept>, settings: &State<Settings>) -> (ContentType, &'static str) {
+    let wants_html = accept.is_some_and(|accept| accept.iter().any(|media_type| media_type.is_html()));
+    if wants_html && settings.playground {
+        return (ContentType::HTML, PLAYGROUND_HTML);
+    }
+
+    (ContentType::Plain, "This is a ipa_server, running on Rocket (Rust). You probably meant to do a POST request, see GET /help for what goes in it")
+}
+
+#[get("/playground")]
+fn playground(settings: &State<Settings>) -> Option<(ContentType, &'static str)> {
+    settings.playground.then_some((ContentType::HTML, PLAYGROUND_HTML))
 }
 
 #[options("/<_..>")]
 fn all_options() {}
 
-fn generic_language_from_code(master_code: LanguageCode) -> String {
-    master_code.as_str().get(0..2).unwrap().to_string()
+// The plain filename gets whatever survives as ASCII, filename* has the real thing for clients that understand it
+fn content_disposition(language: &str, ipa: &str, extension: &str) -> Header<'static> {
+    let filename = format!("{}-{ipa}.{extension}", language.replace(' ', "_"));
+    let ascii_filename: String = filename.chars()
+        .filter(|character| character.is_ascii_alphanumeric() || matches!(character, '-' | '_' | '.'))
+        .collect();
+    let encoded_filename: String = filename.bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect();
+
+    Header::new("Content-Disposition", format!("attachment; filename=\"{ascii_filename}\"; filename*=UTF-8''{encoded_filename}"))
+}
+
+// What Polly accepts, leaving it unset gets its own per-engine default
+fn allowed_sample_rates(output_format: &OutputFormat, engine: &Engine) -> &'static [&'static str] {
+    match (output_format, engine) {
+        (OutputFormat::Pcm, _) => &["8000", "16000"],
+        (_, Engine::Standard) => &["8000", "16000", "22050"],
+        _ => &["8000", "16000", "22050", "24000"],
+    }
 }
 
-#[rocket::main]
-async fn main() {
-    let shared_config = aws_config::from_env().region(Region::new("eu-west-2")).load().await;
-    let polly_client = Client::new(&shared_config);
+// The language without its region, so en-GB and en-US voices both count as English.
+// Some are three letters (cmn-CN, arb), which a fixed two-letter prefix used to mangle.
+fn generic_language_from_code(master_code: &LanguageCode) -> Option<String> {
+    let language = master_code.as_str().split('-').next()?;
+    if !(2..=3).contains(&language.len()) || !language.chars().all(|character| character.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    Some(language.to_ascii_lowercase())
+}
 
-    let mut all_voices: HashMap<String, Vec<VoiceId>> = HashMap::new();
+type Speakers = HashMap<String, Vec<Speaker>>;
 
-    let voices_result = polly_client.describe_voices().send().await.expect("Please (re)initialise your AWS credentials. See https://docs.aws.amazon.com/cli/latest/userguide/cli-configure-files.html");
-    for voice in voices_result.voices.unwrap() {
-        if !voice.clone().supported_engines.unwrap().contains(&Engine::Standard) {
+// Straight to Polly with an English voice, skipping the caches so it proves Polly actually works.
+// Returns the voice it used.
+async fn self_test(synthesizer: &dyn SpeechSynthesizer, speakers: &Speakers, timeout: Duration) -> Result<String, String> {
+    let speaker = speakers.get("en").and_then(|speakers| speakers.first()).ok_or("no English voices are loaded")?;
+    let key = SynthesisKey {
+        text: ssml::build_ssml(&ssml::SsmlParams {
+            alphabet: "ipa",
+            words: &[SELF_TEST_IPA],
+            break_ms: None,
+            language_code: None,
+            carrier: None,
+            effect: None,
+            prosody: &ssml::Prosody::default(),
+        }),
+        text_type: TextType::Ssml,
+        voice: speaker.id.clone(),
+        engine: speaker.best_engine(),
+        output_format: OutputFormat::OggVorbis,
+        sample_rate: None,
+        speech_marks: Vec::new(),
+        lexicon_names: Vec::new(),
+        language_code: None,
+    };
+
+    match rocket::tokio::time::timeout(timeout, synthesizer.synthesize(&key)).await {
+        Ok(Ok(audio)) if !audio.is_empty() => Ok(speaker.id.as_str().to_string()),
+        Ok(Ok(_)) => Err("Polly returned no audio".to_string()),
+        Ok(Err(error)) => Err(format!("{} ({})", error.code(), error.status())),
+        Err(_) => Err(format!("timed out after {}s", timeout.as_secs())),
+    }
+}
+
+// Waits for voices if they haven't loaded yet, then retries until the self-test passes, so a slow start
+// or a blip at boot doesn't leave /healthz unready until a restart
+async fn keep_self_testing(synthesizer: Box<dyn SpeechSynthesizer>, speakers: Arc<RwLock<Arc<Speakers>>>, timeout: Duration, passed: Arc<AtomicBool>) {
+    loop {
+        let loaded_speakers = speakers.read().unwrap().clone();
+        if !loaded_speakers.is_empty() {
+            match self_test(synthesizer.as_ref(), &loaded_speakers, timeout).await {
+                Ok(voice) => {
+                    info!("Self-test passed, {voice} synthesized {SELF_TEST_IPA}");
+                    passed.store(true, Ordering::Relaxed);
+                    return;
+                }
+                Err(err) => error!("Self-test failed, reporting not ready on /healthz and retrying in {VOICE_RETRY_SECS}s: {err}"),
+            }
+        }
+        rocket::tokio::time::sleep(Duration::from_secs(VOICE_RETRY_SECS)).await;
+    }
+}
+
+// IPA_ENGINES, every engine we know how to use when unset or nothing in it is usable
+fn engines_from_env() -> Vec<Engine> {
+    let mut engines = Vec::new();
+    for engine_name in config::env_list("IPA_ENGINES") {
+        match NAME_TO_ENGINE.get(engine_name.as_str()) {
+            Some(engine) if !engines.contains(engine) => engines.push(engine.clone()),
+            Some(_) => {}
+            None => warn!("Ignoring unknown engine {engine_name} in IPA_ENGINES"),
+        }
+    }
+    if engines.is_empty() {
+        engines = NAME_TO_ENGINE.values().cloned().collect();
+    }
+
+    engines
+}
+
+// max_voices of 0 keeps every voice
+async fn load_speakers(client: &Client, allowed_engines: &[Engine], max_voices: usize) -> Result<Speakers, SdkError<DescribeVoicesError>> {
+    let voices_result = client.describe_voices().send().await?;
+    Ok(build_speakers(voices_result.voices.unwrap(), allowed_engines, max_voices))
+}
+
+fn build_speakers(voices: Vec<Voice>, allowed_engines: &[Engine], max_voices: usize) -> Speakers {
+    let mut all_voices: Speakers = HashMap::new();
+
+    for voice in voices {
+        let engines: Vec<Engine> = voice.supported_engines().unwrap_or_default().iter()
+            .filter(|engine| allowed_engines.contains(engine))
+            .cloned()
+            .collect();
+        if engines.is_empty() {
             continue;
         }
 
@@ -135,28 +1429,790 @@ async fn main() {
         let mut voice_languages: Vec<LanguageCode> = Vec::from(voice.additional_language_codes().unwrap_or_default());
         voice_languages.push(main_language);
 
-        // println!("Language for {}: {} ({:#?}). Additional: {:#?}", voice.name().unwrap(), voice.language_name().unwrap(), voice.language_code().unwrap(), voice.additional_language_codes().unwrap_or_default());
-
         for voice_language in voice_languages {
-            // Convert to generic language code by taking first two characters.
-            // I hate it but what can you do.
-            let generic_language = generic_language_from_code(voice_language).to_string();
-            // println!("{} speaks {}", voice.name().unwrap(), generic_language);
-            all_voices.entry(generic_language).or_insert(Vec::new()).push(voice.id().unwrap().clone());
+            let Some(generic_language) = generic_language_from_code(&voice_language) else {
+                warn!("Skipping {} for {}, can't tell what language that is", voice.id().unwrap().as_str(), voice_language.as_str());
+                continue;
+            };
+            let language_speakers = all_voices.entry(generic_language).or_default();
+            // Additional languages often share a prefix with the main one (en-GB speaking en-US too),
+            // and listing the voice twice would make it twice as likely to get picked
+            if let Some(speaker) = language_speakers.iter_mut().find(|speaker| Some(&speaker.id) == voice.id()) {
+                if !speaker.language_codes.contains(&voice_language) {
+                    speaker.language_codes.push(voice_language);
+                }
+                continue;
+            }
+            language_speakers.push(Speaker {
+                id: voice.id().unwrap().clone(),
+                engines: engines.clone(),
+                gender: voice.gender().cloned(),
+                language_codes: vec![voice_language],
+            });
+        }
+    }
+    if max_voices > 0 {
+        truncate_speakers(&mut all_voices, max_voices);
+    }
+
+    all_voices
+}
+
+// By id rather than the order describe_voices happened to use, so the same voices are kept every time
+fn truncate_speakers(speakers: &mut Speakers, max_voices: usize) {
+    for (generic_language, language_speakers) in speakers.iter_mut() {
+        if language_speakers.len() > max_voices {
+            language_speakers.sort_by(|a, b| a.id.as_str().cmp(b.id.as_str()));
+            debug!("Keeping {max_voices} of the {} voices for {generic_language}", language_speakers.len());
+            language_speakers.truncate(max_voices);
         }
     }
+}
+
+// Retries quickly until something loads, then picks up voices AWS adds or retires every refresh_interval.
+// A failed refresh keeps the voices we already have.
+async fn refresh_speakers(client: Client, speakers: Arc<RwLock<Arc<Speakers>>>, engines: Vec<Engine>, max_voices: usize, refresh_interval: Option<Duration>) {
+    loop {
+        let loaded = !speakers.read().unwrap().is_empty();
+        let delay = match (loaded, refresh_interval) {
+            (false, _) => Duration::from_secs(VOICE_RETRY_SECS),
+            (true, Some(refresh_interval)) => refresh_interval,
+            (true, None) => return,
+        };
+        rocket::tokio::time::sleep(delay).await;
+
+        match load_speakers(&client, &engines, max_voices).await {
+            Ok(loaded_speakers) => {
+                let previous_voices = voice_set(&speakers.read().unwrap());
+                let loaded_voices = voice_set(&loaded_speakers);
+                let added: Vec<&String> = loaded_voices.difference(&previous_voices).collect();
+                let removed: Vec<&String> = previous_voices.difference(&loaded_voices).collect();
+                if !added.is_empty() || !removed.is_empty() {
+                    info!("Voices changed, now {} languages. Added: {added:?}. Removed: {removed:?}", loaded_speakers.len());
+                }
+
+                *speakers.write().unwrap() = Arc::new(loaded_speakers);
+            }
+            Err(err) if loaded => error!("Failed to refresh voices, keeping the ones already loaded: {err}"),
+            Err(err) => error!("Failed to load voices, retrying in {VOICE_RETRY_SECS}s: {err}"),
+        }
+    }
+}
+
+// language/voice pairs, so a voice gaining or losing a language counts as a change too
+fn voice_set(speakers: &Speakers) -> HashSet<String> {
+    speakers.iter()
+        .flat_map(|(generic_language, speakers)| speakers.iter().map(move |speaker| format!("{generic_language}/{}", speaker.id.as_str())))
+        .collect()
+}
+
+// Rocket sizes its runtime from its own config before an async main gets to run, so IPA_WORKERS needs the runtime built here
+fn main() {
+    let workers = config::env_or("IPA_WORKERS", rocket::Config::from(rocket::Config::figment()).workers);
+    let runtime = rocket::tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(workers.max(1))
+        .thread_name("rocket-worker-thread")
+        .enable_all()
+        .build()
+        .expect("failed to create the async runtime");
+
+    runtime.block_on(serve(workers.max(1)));
+}
+
+// IPA_AWS_REGION, then the usual AWS_REGION/profile lookup, then where this has always run
+fn region_provider() -> RegionProviderChain {
+    RegionProviderChain::first_try(std::env::var("IPA_AWS_REGION").ok().filter(|region| !region.is_empty()).map(Region::new))
+        .or_default_provider()
+        .or_else(DEFAULT_AWS_REGION)
+}
+
+async fn serve(workers: usize) {
+    // Building sets up Rocket's logger, so do it before anything below wants to log
+    logging::init();
+    #[cfg(feature = "otel")]
+    telemetry::init();
+    let rocket = rocket::build();
+
+    // Checked before anything else, a typo here would otherwise only show up as every request failing
+    let language_map = match LanguageMap::from_env() {
+        Ok(language_map) => language_map,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    let shared_config = aws_config::from_env().region(region_provider()).load().await;
+    info!("Using AWS region {}", shared_config.region().map_or(DEFAULT_AWS_REGION, |region| region.as_ref()));
+    // synthesize_speech does its own retrying so that every attempt gets logged
+    let polly_config = aws_sdk_polly::config::Builder::from(&shared_config)
+        .retry_config(RetryConfig::disabled())
+        .build();
+    let polly_client = Client::from_conf(polly_config);
+
+    // Starting without voices beats not starting, requests get a 503 until the retries get through
+    let speakers = Arc::new(RwLock::new(Arc::new(Speakers::new())));
+    let engines = engines_from_env();
+    info!("Loading voices for the {} engines", engines.iter().map(Engine::as_str).collect::<Vec<_>>().join(", "));
+    let max_voices = config::env_or("IPA_MAX_VOICES_PER_LANGUAGE", 0);
+    match load_speakers(&polly_client, &engines, max_voices).await {
+        // Polly answered, so retrying won't help until IPA_ENGINES changes. /healthz stays unready.
+        Ok(loaded_speakers) if loaded_speakers.is_empty() => error!("Polly has no voices for the {} engines, every request will fail. Check IPA_ENGINES and IPA_AWS_REGION", engines.iter().map(Engine::as_str).collect::<Vec<_>>().join(", ")),
+        Ok(loaded_speakers) => *speakers.write().unwrap() = Arc::new(loaded_speakers),
+        Err(err) => error!("Failed to load voices, retrying in the background. Please (re)initialise your AWS credentials if this keeps happening. See https://docs.aws.amazon.com/cli/latest/userguide/cli-configure-files.html: {err}"),
+    }
+    let refresh_interval = match config::env_or("IPA_VOICE_REFRESH_SECS", DEFAULT_VOICE_REFRESH_SECS) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    rocket::tokio::spawn(refresh_speakers(polly_client.clone(), speakers.clone(), engines, max_voices, refresh_interval));
+
+    let cache_capacity = config::env_or("IPA_CACHE_CAPACITY", DEFAULT_CACHE_CAPACITY);
+    let shared_cache = match config::env_or("IPA_S3_CACHE_BUCKET", String::new()) {
+        bucket if bucket.is_empty() => None,
+        bucket => {
+            info!("Sharing synthesized audio through S3 bucket {bucket}");
+            Some(S3Cache::new(aws_sdk_s3::Client::new(&shared_config), bucket))
+        }
+    };
+    let timeout = Duration::from_secs(config::env_or("IPA_SYNTHESIS_TIMEOUT_SECS", DEFAULT_SYNTHESIS_TIMEOUT_SECS));
+    // Costs a Polly call every startup, hence off by default
+    let self_test_passed = Arc::new(AtomicBool::new(true));
+    if matches!(config::env_or("IPA_SELFTEST", String::new()).to_ascii_lowercase().as_str(), "1" | "true") {
+        self_test_passed.store(false, Ordering::Relaxed);
+        rocket::tokio::spawn(keep_self_testing(Box::new(PollySynthesizer::new(polly_client.clone())), speakers.clone(), timeout, self_test_passed.clone()));
+    }
+
+    let rate_limits = RateLimits::from_env();
+    info!("Rate limiting to {} requests per hour for each API key or IP", rate_limits.per_hour());
 
     let polly = Polly {
-        client: polly_client,
-        speakers: all_voices,
+        synthesizer: Box::new(PollySynthesizer::new(polly_client)),
+        speakers,
+        cache: Mutex::new(LruCache::new(cache_capacity)),
+        shared_cache,
+        in_flight: SingleFlight::new(),
+        round_robin: RoundRobin::default(),
+        timeout,
+        permits: Semaphore::new(match config::env_or("IPA_MAX_CONCURRENT_SYNTHESES", DEFAULT_MAX_CONCURRENT_SYNTHESES) {
+            0 => Semaphore::MAX_PERMITS,
+            permits => permits,
+        }),
+        permit_wait: Duration::from_millis(PERMIT_WAIT_MS),
+        circuit_breaker: CircuitBreaker::from_env(),
+        max_audio_bytes: match config::env_or("IPA_MAX_AUDIO_BYTES", DEFAULT_MAX_AUDIO_BYTES) {
+            0 => usize::MAX,
+            max_audio_bytes => max_audio_bytes,
+        },
+        self_test_passed,
     };
 
-    let _ = rocket::build()
-        .attach(cors::CORS)
-        .attach(rocket_governor::LimitHeaderGen::default())
+    let ip_denylist = IpDenylist::from_env();
+    #[cfg(unix)]
+    ip_denylist.reload_on_hangup();
+
+    let shutdown_grace_secs = shutdown::grace_secs();
+    // Only when set, otherwise Rocket.toml and ROCKET_ADDRESS/ROCKET_PORT decide as usual
+    let mut figment = rocket.figment().clone();
+    if let Some(address) = config::env_opt::<IpAddr>("IPA_BIND_ADDRESS") {
+        figment = figment.merge(("address", address));
+    }
+    if let Some(port) = config::env_opt::<u16>("IPA_PORT") {
+        figment = figment.merge(("port", port));
+    }
+    if let (Ok(address), Ok(port)) = (figment.extract_inner::<IpAddr>("address"), figment.extract_inner::<u16>("port")) {
+        info!("Binding to {}", SocketAddr::new(address, port));
+    }
+    // Rocket's default, 5 seconds, unless ROCKET_KEEP_ALIVE says otherwise. 0 turns keep-alive off.
+    // Connections can't be capped the same way, Rocket 0.5 doesn't let anything but its own listener accept them
+    let keep_alive_secs = config::env_or("IPA_KEEP_ALIVE_SECS", rocket.figment().extract_inner::<u32>("keep_alive").unwrap_or(5));
+    info!("Running {workers} workers, keep-alive {}", if keep_alive_secs == 0 { "off".to_string() } else { format!("{keep_alive_secs}s") });
+    let figment = figment
+        .merge(("workers", workers))
+        .merge(("keep_alive", keep_alive_secs))
+        .merge(("shutdown.grace", shutdown_grace_secs))
+        .merge(("limits.json", config::env_or("IPA_MAX_BODY_BYTES", DEFAULT_MAX_BODY_BYTES)));
+
+    let allowed_origins = cors::AllowedOrigins::from_env();
+    let rocket = rocket
+        .configure(figment)
+        .attach(cors::CORS::new(allowed_origins.clone()))
+        .attach(rate_limit::RateLimitHeaders)
+        .attach(metrics::RateLimitCounter)
+        .attach(logging::RequestLogger::from_env())
+        .attach(shutdown::ShutdownLogger::new(shutdown_grace_secs))
         .manage(polly)
-        .mount("/", routes![index, speak, all_options])
-        .register("/", catchers![rocket_validation::validation_catcher])
-        .launch()
-        .await;
+        .manage(ApiKeys::from_env())
+        .manage(allowed_origins)
+        .manage(ip_denylist)
+        .manage(rate_limits)
+        .manage(SharedLanguageMap::new(language_map))
+        .manage(Settings::from_env())
+        .manage(Metrics::from_env())
+        .mount("/", routes![index, playground, speak, speak_get, speak_preview, speak_stream, speak_marks, speak_ssml, speak_batch, cache_warm, voices, languages, rate_limit_status, healthz, help, version, metrics_endpoint, admin::reload, all_options])
+        .register("/", catchers![error::bad_request_catcher, ip_filter::forbidden_catcher, error::payload_too_large_catcher, error::unprocessable_entity_catcher, auth::unauthorized_catcher, rate_limit::too_many_requests_catcher]);
+    #[cfg(feature = "websocket")]
+    let rocket = rocket.mount("/", routes![websocket::speak_ws]);
+    #[cfg(feature = "compression")]
+    let rocket = rocket.attach(compression::Gzip);
+
+    let _ = rocket.launch().await;
+
+    #[cfg(feature = "otel")]
+    telemetry::shutdown();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    // Counts how often it's asked for audio, which is always the text back as bytes
+    #[derive(Clone, Default)]
+    struct FakeSynthesizer {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl FakeSynthesizer {
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[rocket::async_trait]
+    impl SpeechSynthesizer for FakeSynthesizer {
+        async fn stream(&self, key: &SynthesisKey) -> Result<AudioStream, ApiError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::pin(std::io::Cursor::new(key.text.clone().into_bytes())))
+        }
+    }
+
+    fn test_polly(synthesizer: FakeSynthesizer) -> Polly {
+        Polly {
+            synthesizer: Box::new(synthesizer),
+            speakers: Arc::new(RwLock::new(Arc::new(Speakers::new()))),
+            cache: Mutex::new(LruCache::new(DEFAULT_CACHE_CAPACITY)),
+            shared_cache: None,
+            in_flight: SingleFlight::new(),
+            round_robin: RoundRobin::default(),
+            timeout: Duration::from_secs(5),
+            permits: Semaphore::new(DEFAULT_MAX_CONCURRENT_SYNTHESES),
+            permit_wait: Duration::from_millis(PERMIT_WAIT_MS),
+            circuit_breaker: CircuitBreaker::from_env(),
+            max_audio_bytes: DEFAULT_MAX_AUDIO_BYTES,
+            self_test_passed: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    fn key(text: &str) -> SynthesisKey {
+        SynthesisKey {
+            text: text.to_string(),
+            text_type: TextType::Ssml,
+            voice: VoiceId::Joanna,
+            engine: Engine::Neural,
+            output_format: OutputFormat::Mp3,
+            sample_rate: None,
+            speech_marks: Vec::new(),
+            lexicon_names: Vec::new(),
+            language_code: None,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn repeat_requests_come_from_the_cache() {
+        let synthesizer = FakeSynthesizer::default();
+        let polly = test_polly(synthesizer.clone());
+        let metrics = Metrics::default();
+
+        assert_eq!(polly.synthesize_speech(key("hello"), &metrics).await.unwrap(), "hello");
+        assert_eq!(polly.synthesize_speech(key("hello"), &metrics).await.unwrap(), "hello");
+        assert_eq!(synthesizer.calls(), 1);
+
+        polly.synthesize_speech(key("goodbye"), &metrics).await.unwrap();
+        assert_eq!(synthesizer.calls(), 2);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("ipa_cache_lookups_total{cache=\"memory\",result=\"hit\"} 1\n"), "{rendered}");
+        assert!(rendered.contains("ipa_cache_lookups_total{cache=\"memory\",result=\"miss\"} 2\n"), "{rendered}");
+    }
+
+    #[rocket::async_test]
+    async fn turns_away_a_request_over_the_limit() {
+        let polly = Polly { permits: Semaphore::new(2), permit_wait: Duration::from_millis(20), ..test_polly(FakeSynthesizer::default()) };
+        let first = polly.acquire_permit().await.unwrap();
+        let _second = polly.acquire_permit().await.unwrap();
+
+        let error = polly.acquire_permit().await.err().unwrap();
+        assert_eq!(error.status(), Status::ServiceUnavailable);
+        assert_eq!(error.code(), "overloaded");
+
+        // Fine again once one finishes
+        drop(first);
+        assert!(polly.acquire_permit().await.is_ok());
+    }
+
+    fn set_speakers(polly: &Polly, generic_language: &str, speakers: Vec<Speaker>) {
+        *polly.speakers.write().unwrap() = Arc::new(Speakers::from([(generic_language.to_string(), speakers)]));
+    }
+
+    fn speech_response(audio: Option<&'static str>) -> SpeechResponse {
+        SpeechResponse {
+            audio: audio.map(|audio| Bytes::from_static(audio.as_bytes())),
+            etag: "\"abc\"".to_string(),
+            content_type: ContentType::new("audio", "mpeg"),
+            content_disposition: Header::new("Content-Disposition", "inline"),
+            language: "English".to_string(),
+            language_code: LanguageCode::EnUs,
+            voice: VoiceId::Joanna,
+            engine: Engine::Neural,
+            ipa_length: 5,
+            duration_ms: None,
+            warnings: None,
+            voice_fallback: false,
+        }
+    }
+
+    #[get("/audio")]
+    fn audio_route() -> SpeechResponse {
+        speech_response(Some("audio"))
+    }
+
+    #[get("/not-modified")]
+    fn not_modified_route() -> SpeechResponse {
+        speech_response(None)
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let etag = "\"abc\"";
+        let if_none_match = |header: &str| IfNoneMatch(Some(header.to_string()));
+        assert!(if_none_match("\"abc\"").matches(etag));
+        assert!(if_none_match("W/\"abc\"").matches(etag));
+        assert!(if_none_match("\"xyz\", W/\"abc\"").matches(etag));
+        assert!(if_none_match("*").matches(etag));
+        assert!(!if_none_match("\"xyz\"").matches(etag));
+        assert!(!if_none_match("abc").matches(etag));
+        assert!(!IfNoneMatch(None).matches(etag));
+    }
+
+    #[rocket::async_test]
+    async fn matching_etag_skips_synthesis() {
+        let synthesizer = FakeSynthesizer::default();
+        let (languages, settings, metrics) = (LanguageMap::default(), Settings::from_env(), Metrics::default());
+        let data = request(r#"{"ipa": "həˈloʊ", "language": "English"}"#);
+        let etag = {
+            let polly = test_polly(synthesizer.clone());
+            set_speakers(&polly, "en", vec![speaker("Joanna", vec![Engine::Neural])]);
+            let speech = resolve_and_synthesize(&data, &IfNoneMatch(None), &polly, &languages, &settings, &metrics).await.unwrap();
+            assert!(speech.audio.is_some());
+            speech.etag
+        };
+
+        // A fresh Polly, so it can't be the memory cache that saves the call
+        let polly = test_polly(synthesizer.clone());
+        set_speakers(&polly, "en", vec![speaker("Joanna", vec![Engine::Neural])]);
+        let speech = resolve_and_synthesize(&data, &IfNoneMatch(Some(format!("W/{etag}"))), &polly, &languages, &settings, &metrics).await.unwrap();
+        assert!(speech.audio.is_none());
+        assert_eq!(speech.etag, etag);
+        assert_eq!(synthesizer.calls(), 1);
+    }
+
+    #[test]
+    fn no_audio_is_a_304() {
+        let client = rocket::local::blocking::Client::tracked(rocket::build().mount("/", routes![audio_route, not_modified_route])).unwrap();
+
+        let response = client.get("/not-modified").dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+        assert_eq!(response.headers().get_one("ETag"), Some("\"abc\""));
+        assert_eq!(response.into_bytes(), None);
+
+        let response = client.get("/audio").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("ETag"), Some("\"abc\""));
+        assert_eq!(response.into_string().as_deref(), Some("audio"));
+    }
+
+    #[test]
+    fn range_gets_part_of_the_audio() {
+        let client = rocket::local::blocking::Client::tracked(rocket::build().mount("/", routes![audio_route])).unwrap();
+
+        let response = client.get("/audio").header(Header::new("Range", "bytes=1-3")).dispatch();
+        assert_eq!(response.status(), Status::PartialContent);
+        assert_eq!(response.headers().get_one("Content-Range"), Some("bytes 1-3/5"));
+        assert_eq!(response.headers().get_one("Accept-Ranges"), Some("bytes"));
+        assert_eq!(response.into_string().as_deref(), Some("udi"));
+
+        let response = client.get("/audio").header(Header::new("Range", "bytes=5-")).dispatch();
+        assert_eq!(response.status(), Status::RangeNotSatisfiable);
+        assert_eq!(response.headers().get_one("Content-Range"), Some("bytes */5"));
+
+        // Only if the client's copy is this one
+        let response = client.get("/audio").header(Header::new("Range", "bytes=1-3")).header(Header::new("If-Range", "\"old\"")).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Accept-Ranges"), Some("bytes"));
+        assert_eq!(response.into_string().as_deref(), Some("audio"));
+    }
+
+    fn speaker(id: &str, engines: Vec<Engine>) -> Speaker {
+        Speaker { id: VoiceId::from(id), engines, gender: None, language_codes: vec![LanguageCode::EnUs] }
+    }
+
+    fn request(json: &str) -> RequestData {
+        rocket::serde::json::from_str(json).unwrap()
+    }
+
+    fn validation_error(json: &str) -> Option<&'static str> {
+        request(json).validate().err().map(|errors| ApiError::from_validation_errors(&errors).code())
+    }
+
+    #[test]
+    fn blank_ipa_is_empty_not_too_short() {
+        assert_eq!(validation_error(r#"{"ipa": ""}"#), Some("ipa_empty"));
+        assert_eq!(validation_error(r#"{"ipa": " "}"#), Some("ipa_empty"));
+        assert_eq!(validation_error(r#"{"ipa": "\t"}"#), Some("ipa_empty"));
+        assert_eq!(validation_error(r#"{"ipa": " ə "}"#), None);
+    }
+
+    // English only, with the one voice unless the test sets others
+    fn english_polly() -> Polly {
+        let polly = test_polly(FakeSynthesizer::default());
+        set_speakers(&polly, "en", vec![speaker("Joanna", vec![Engine::Standard, Engine::Neural])]);
+        polly
+    }
+
+    fn resolve_error(polly: &Polly, json: &str) -> Option<&'static str> {
+        resolve(&request(json), polly, &LanguageMap::default(), &Settings::from_env()).err().map(|error| error.code())
+    }
+
+    // Hands the test the other end of the audio, so it can see what the client gets before the clip is done
+    struct PipeSynthesizer {
+        audio: Mutex<Option<rocket::tokio::io::DuplexStream>>,
+    }
+
+    #[rocket::async_trait]
+    impl SpeechSynthesizer for PipeSynthesizer {
+        async fn stream(&self, _key: &SynthesisKey) -> Result<AudioStream, ApiError> {
+            Ok(Box::pin(self.audio.lock().unwrap().take().unwrap()))
+        }
+    }
+
+    #[rocket::async_test]
+    async fn streamed_audio_arrives_before_it_is_finished() {
+        use rocket::tokio::io::AsyncWriteExt;
+        use rocket::tokio::time::timeout;
+
+        let (mut synthesized, audio) = rocket::tokio::io::duplex(STREAM_CHUNK_SIZE);
+        let polly = Polly { synthesizer: Box::new(PipeSynthesizer { audio: Mutex::new(Some(audio)) }), ..english_polly() };
+        let rocket = {
+            let _env = config::lock_env();
+            rocket::build()
+                .manage(polly)
+                .manage(ApiKeys::from_env())
+                .manage(IpDenylist::from_env())
+                .manage(RateLimits::from_env())
+                .manage(SharedLanguageMap::new(LanguageMap::default()))
+                .manage(Settings::from_env())
+                .manage(Metrics::default())
+                .mount("/", routes![speak_stream])
+        };
+        let client = rocket::local::asynchronous::Client::tracked(rocket).await.unwrap();
+
+        synthesized.write_all(b"first").await.unwrap();
+        // Anything waiting on the whole body would hang here, the synthesizer isn't done yet
+        let request = client.post("/speak/stream").header(ContentType::JSON).body(r#"{"ipa": "ə", "language": "English"}"#);
+        let mut response = timeout(Duration::from_secs(5), request.dispatch()).await.expect("the response waited for the whole clip");
+        assert_eq!(response.status(), Status::Ok);
+        let mut chunk = [0; 5];
+        timeout(Duration::from_secs(5), response.read_exact(&mut chunk)).await.expect("the first chunk never arrived").unwrap();
+        assert_eq!(&chunk, b"first");
+
+        synthesized.write_all(b" and the rest").await.unwrap();
+        drop(synthesized);
+        let mut rest = Vec::new();
+        timeout(Duration::from_secs(5), response.read_to_end(&mut rest)).await.unwrap().unwrap();
+        assert_eq!(rest, b" and the rest");
+    }
+
+    fn several_english_voices() -> Polly {
+        let polly = test_polly(FakeSynthesizer::default());
+        let voices = ["Salli", "Joanna", "Matthew", "Ivy", "Kendra"].into_iter()
+            .map(|voice| speaker(voice, vec![Engine::Standard, Engine::Neural]))
+            .collect();
+        set_speakers(&polly, "en", voices);
+        polly
+    }
+
+    fn resolved_voice(polly: &Polly, json: &str) -> String {
+        resolve(&request(json), polly, &LanguageMap::default(), &Settings::from_env()).map(|resolved| resolved.key.voice.as_str().to_string()).unwrap()
+    }
+
+    #[test]
+    fn same_seed_same_voice() {
+        let polly = several_english_voices();
+        let mut voices = HashSet::new();
+        for seed in 0..20 {
+            let json = format!(r#"{{"ipa": "həˈloʊ", "language": "English", "seed": {seed}}}"#);
+            let voice = resolved_voice(&polly, &json);
+            for _ in 0..5 {
+                assert_eq!(resolved_voice(&polly, &json), voice, "seed {seed}");
+            }
+            voices.insert(voice);
+        }
+        // Still varied across seeds
+        assert!(voices.len() > 1);
+    }
+
+    #[test]
+    fn each_strategy_picks_as_it_says() {
+        let polly = several_english_voices();
+        let pick = |strategy: &str| resolved_voice(&polly, &format!(r#"{{"ipa": "ə", "language": "English", "strategy": "{strategy}"}}"#));
+
+        // Alphabetically, not in the order the voices were loaded
+        assert!((0..5).all(|_| pick("first") == "Ivy"));
+        let turns: Vec<String> = (0..6).map(|_| pick("round-robin")).collect();
+        assert_eq!(turns, ["Ivy", "Joanna", "Kendra", "Matthew", "Salli", "Ivy"]);
+        let picked: HashSet<String> = (0..200).map(|_| pick("random")).collect();
+        assert_eq!(picked.len(), 5);
+
+        assert_eq!(resolve_error(&polly, r#"{"ipa": "ə", "language": "English", "strategy": "loudest"}"#), Some("unsupported_strategy"));
+    }
+
+    #[test]
+    fn ipa_length_is_in_characters() {
+        // Three characters and six bytes each, counting the combining tilde
+        let symbols = "θ̃ˈ";
+        let polly = english_polly();
+        let thirty = symbols.repeat(10);
+        assert!(thirty.len() > MAX_IPA_LENGTH as usize);
+        assert_eq!(resolve_error(&polly, &format!(r#"{{"ipa": "{thirty}", "language": "English"}}"#)), None);
+        let over = symbols.repeat(17);
+        assert_eq!(over.chars().count() as u64, MAX_IPA_LENGTH + 1);
+        assert_eq!(resolve_error(&polly, &format!(r#"{{"ipa": "{over}", "language": "English"}}"#)), Some("ipa_too_long"));
+    }
+
+    // Blocks on its own runtime so the environment lock isn't held across an await
+    #[test]
+    fn region_falls_back_through_the_chain() {
+        let _env = config::lock_env();
+        // Nothing from the machine running the tests, and no waiting on an instance metadata endpoint
+        for (name, value) in [("AWS_CONFIG_FILE", "/nonexistent/config"), ("AWS_SHARED_CREDENTIALS_FILE", "/nonexistent/credentials"), ("AWS_EC2_METADATA_DISABLED", "true")] {
+            std::env::set_var(name, value);
+        }
+        std::env::remove_var("AWS_DEFAULT_REGION");
+        let runtime = rocket::tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let region = || runtime.block_on(region_provider().region()).map(|region| region.as_ref().to_string());
+
+        std::env::set_var("IPA_AWS_REGION", "us-west-2");
+        std::env::set_var("AWS_REGION", "eu-central-1");
+        assert_eq!(region().as_deref(), Some("us-west-2"));
+        std::env::set_var("IPA_AWS_REGION", "");
+        assert_eq!(region().as_deref(), Some("eu-central-1"));
+        std::env::remove_var("IPA_AWS_REGION");
+        assert_eq!(region().as_deref(), Some("eu-central-1"));
+        std::env::remove_var("AWS_REGION");
+        assert_eq!(region().as_deref(), Some(DEFAULT_AWS_REGION));
+    }
+
+    fn voice(id: VoiceId, language_code: LanguageCode, additional_language_codes: &[LanguageCode]) -> Voice {
+        additional_language_codes.iter().cloned()
+            .fold(Voice::builder(), |builder, additional| builder.additional_language_codes(additional))
+            .id(id)
+            .language_code(language_code)
+            .gender(Gender::Female)
+            .supported_engines(Engine::Standard)
+            .supported_engines(Engine::Neural)
+            .build()
+    }
+
+    fn voice_ids(speakers: &Speakers, generic_language: &str) -> Vec<String> {
+        let mut ids: Vec<String> = speakers[generic_language].iter().map(|speaker| speaker.id.as_str().to_string()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn overlapping_language_codes_list_a_voice_once() {
+        let voices = vec![
+            // British, but also listed as speaking American English and Spanish
+            voice(VoiceId::Amy, LanguageCode::EnGb, &[LanguageCode::EnUs, LanguageCode::EsUs]),
+            voice(VoiceId::Joanna, LanguageCode::EnUs, &[]),
+            voice(VoiceId::Lucia, LanguageCode::EsEs, &[]),
+        ];
+        let speakers = build_speakers(voices, &[Engine::Standard, Engine::Neural], 0);
+
+        assert_eq!(voice_ids(&speakers, "en"), ["Amy", "Joanna"]);
+        assert_eq!(voice_ids(&speakers, "es"), ["Amy", "Lucia"]);
+        let amy = speakers["en"].iter().find(|speaker| speaker.id == VoiceId::Amy).unwrap();
+        assert_eq!(amy.language_codes, [LanguageCode::EnUs, LanguageCode::EnGb]);
+    }
+
+    #[test]
+    fn voices_without_an_allowed_engine_are_left_out() {
+        let speakers = build_speakers(vec![voice(VoiceId::Joanna, LanguageCode::EnUs, &[])], &[Engine::from("generative")], 0);
+        assert!(speakers.is_empty());
+
+        let speakers = build_speakers(vec![voice(VoiceId::Joanna, LanguageCode::EnUs, &[])], &[Engine::Neural], 0);
+        assert_eq!(speakers["en"][0].engines, [Engine::Neural]);
+    }
+
+    #[test]
+    fn generic_language_handles_short_and_odd_codes() {
+        let generic = |code: &str| generic_language_from_code(&LanguageCode::from(code));
+        assert_eq!(generic("en-GB").as_deref(), Some("en"));
+        assert_eq!(generic("cmn-CN").as_deref(), Some("cmn"));
+        assert_eq!(generic("arb").as_deref(), Some("arb"));
+        assert_eq!(generic("EN-us").as_deref(), Some("en"));
+        assert_eq!(generic(""), None);
+        assert_eq!(generic("e"), None);
+        assert_eq!(generic("e-US"), None);
+        assert_eq!(generic("-US"), None);
+        assert_eq!(generic("engl-US"), None);
+        assert_eq!(generic("é-FR"), None);
+    }
+
+    #[test]
+    fn overlong_language_is_not_echoed_back() {
+        let polly = english_polly();
+        let huge = "a".repeat(10_000);
+        let error = resolve(&request(&format!(r#"{{"ipa": "ə", "language": "{huge}"}}"#)), &polly, &LanguageMap::default(), &Settings::from_env()).err().unwrap();
+        assert_eq!(error.status(), Status::BadRequest);
+        assert_eq!(error.code(), "invalid_language");
+        assert!(!rocket::serde::json::to_string(&error).unwrap().contains(&huge[..MAX_LANGUAGE_LENGTH]));
+
+        let just_over = "a".repeat(MAX_LANGUAGE_LENGTH + 1);
+        assert_eq!(resolve_error(&polly, &format!(r#"{{"ipa": "ə", "language": "{just_over}"}}"#)), Some("invalid_language"));
+        // Right at the limit it's only an unknown language
+        let at_limit = "a".repeat(MAX_LANGUAGE_LENGTH);
+        assert_eq!(resolve_error(&polly, &format!(r#"{{"ipa": "ə", "language": "{at_limit}"}}"#)), Some("unsupported_language"));
+        assert_eq!(resolve_error(&polly, r#"{"ipa": "ə", "language": "English<br>"}"#), Some("invalid_language"));
+        assert_eq!(resolve_error(&polly, r#"{"ipa": "ə", "language": "American English"}"#), None);
+    }
+
+    #[test]
+    fn standard_only_voice_asked_for_neural() {
+        let polly = test_polly(FakeSynthesizer::default());
+        set_speakers(&polly, "en", vec![speaker("Ivy", vec![Engine::Standard]), speaker("Joanna", vec![Engine::Standard, Engine::Neural])]);
+
+        assert_eq!(resolve_error(&polly, r#"{"ipa": "ə", "language": "English", "voice": "Ivy", "engine": "neural"}"#), Some("unsupported_engine"));
+        assert_eq!(resolve_error(&polly, r#"{"ipa": "ə", "language": "English", "voice": "Ivy", "engine": "standard"}"#), None);
+        // Without a voice it goes to one that can
+        for _ in 0..10 {
+            assert_eq!(resolved_voice(&polly, r#"{"ipa": "ə", "language": "English", "engine": "neural"}"#), "Joanna");
+        }
+
+        set_speakers(&polly, "en", vec![speaker("Ivy", vec![Engine::Standard])]);
+        assert_eq!(resolve_error(&polly, r#"{"ipa": "ə", "language": "English", "engine": "neural"}"#), Some("unsupported_engine"));
+    }
+
+    #[test]
+    fn whispering_forces_the_standard_engine() {
+        let polly = english_polly();
+        let resolved_engine = |json: &str| {
+            resolve(&request(json), &polly, &LanguageMap::default(), &Settings::from_env()).map(|resolved| resolved.key.engine.as_str().to_string()).unwrap()
+        };
+        assert_eq!(resolved_engine(r#"{"ipa": "ə", "language": "English"}"#), "neural");
+        assert_eq!(resolved_engine(r#"{"ipa": "ə", "language": "English", "effect": "whispered"}"#), "standard");
+        // Only the whisper is tied to an engine
+        assert_eq!(resolved_engine(r#"{"ipa": "ə", "language": "English", "effect": "drc"}"#), "neural");
+        // Asking for both is a contradiction rather than something to quietly change
+        assert_eq!(resolve_error(&polly, r#"{"ipa": "ə", "language": "English", "effect": "whispered", "engine": "neural"}"#), Some("unsupported_effect"));
+        assert_eq!(resolve_error(&polly, r#"{"ipa": "ə", "language": "English", "effect": "shouted"}"#), Some("unsupported_effect"));
+
+        set_speakers(&polly, "en", vec![speaker("Joanna", vec![Engine::Neural])]);
+        assert_eq!(resolve_error(&polly, r#"{"ipa": "ə", "language": "English", "effect": "whispered"}"#), Some("unsupported_effect"));
+    }
+
+    #[test]
+    fn disallowed_format_is_rejected() {
+        let polly = english_polly();
+        let settings = Settings::with_allowed_formats(&["mp3"]);
+        let resolve_format = |json: &str| {
+            resolve(&request(json), &polly, &LanguageMap::default(), &settings).map(|resolved| resolved.format_name.to_string()).map_err(|error| error.code())
+        };
+
+        // Polly can do ogg, the server just won't
+        assert_eq!(resolve_format(r#"{"ipa": "ə", "language": "English", "format": "ogg"}"#), Err("unsupported_format"));
+        assert_eq!(resolve_format(r#"{"ipa": "ə", "language": "English", "format": "mp3"}"#).as_deref(), Ok("mp3"));
+        assert_eq!(resolve_format(r#"{"ipa": "ə", "language": "English"}"#).as_deref(), Ok("mp3"));
+    }
+
+    #[test]
+    fn ssml_names_the_resolved_language() {
+        let polly = english_polly();
+        let amy = Speaker { id: VoiceId::Amy, engines: vec![Engine::Neural], gender: None, language_codes: vec![LanguageCode::EnGb] };
+        set_speakers(&polly, "en", vec![speaker("Joanna", vec![Engine::Neural]), amy]);
+        let text = |json: &str| resolve(&request(json), &polly, &LanguageMap::default(), &Settings::from_env()).map(|resolved| resolved.key.text).unwrap();
+
+        assert!(text(r#"{"ipa": "ə", "language": "English", "voice": "Joanna"}"#).starts_with("<lang xml:lang='en-US'>"));
+        // The variant wins over the language's own code
+        assert!(text(r#"{"ipa": "ə", "language": "English", "variant": "en-GB"}"#).starts_with("<lang xml:lang='en-GB'>"));
+    }
+
+    #[test]
+    fn carrier_comes_from_the_language_or_the_request() {
+        let polly = english_polly();
+        let text = |json: &str| resolve(&request(json), &polly, &LanguageMap::default(), &Settings::from_env()).map(|resolved| resolved.key.text).map_err(|error| error.code());
+
+        assert_eq!(text(r#"{"ipa": "ə", "language": "English", "carrier": true}"#).as_deref(), Ok("<lang xml:lang='en-US'>The word is <phoneme alphabet='ipa' ph='ə'></phoneme>.</lang>"));
+        assert_eq!(text(r#"{"ipa": "ə", "language": "English", "carrier_template": "Say {} twice"}"#).as_deref(), Ok("<lang xml:lang='en-US'>Say <phoneme alphabet='ipa' ph='ə'></phoneme> twice</lang>"));
+        assert_eq!(text(r#"{"ipa": "ə", "language": "English", "carrier_template": "No placeholder"}"#), Err("invalid_carrier_template"));
+        assert_eq!(text(r#"{"ipa": "ə", "language": "English", "carrier_template": "{} and {}"}"#), Err("invalid_carrier_template"));
+    }
+
+    #[test]
+    fn strict_warns_about_symbols_outside_the_language() {
+        let polly = english_polly();
+        let warnings = |json: &str| resolve(&request(json), &polly, &LanguageMap::default(), &Settings::from_env()).map(|resolved| resolved.warnings).unwrap();
+
+        assert_eq!(warnings(r#"{"ipa": "ħəˈloʊ", "language": "English", "strict": true}"#).as_deref(), Some("U+0127 isn't usually in English"));
+        assert_eq!(warnings(r#"{"ipa": "ħəˈloʊʁ", "language": "English", "strict": true}"#).as_deref(), Some("U+0127, U+0281 aren't usually in English"));
+        assert_eq!(warnings(r#"{"ipa": "həˈloʊ", "language": "English", "strict": true}"#), None);
+        // Opt in only
+        assert_eq!(warnings(r#"{"ipa": "ħəˈloʊ", "language": "English"}"#), None);
+    }
+
+    #[test]
+    fn truncation_keeps_the_first_voices_by_id() {
+        let mut speakers = Speakers::from([
+            ("en".to_string(), ["Salli", "Joanna", "Matthew", "Ivy"].into_iter().map(|id| speaker(id, vec![Engine::Neural])).collect()),
+            ("fr".to_string(), vec![speaker("Lea", vec![Engine::Neural])]),
+        ]);
+        truncate_speakers(&mut speakers, 2);
+
+        assert_eq!(voice_ids(&speakers, "en"), ["Ivy", "Joanna"]);
+        // Already under the cap
+        assert_eq!(voice_ids(&speakers, "fr"), ["Lea"]);
+    }
+
+    #[test]
+    fn max_voices_of_zero_keeps_everything() {
+        let voices = [VoiceId::Joanna, VoiceId::Ivy, VoiceId::Salli].into_iter().map(|id| voice(id, LanguageCode::EnUs, &[])).collect();
+        assert_eq!(build_speakers(voices, &[Engine::Neural], 0)["en"].len(), 3);
+
+        let voices = [VoiceId::Joanna, VoiceId::Ivy, VoiceId::Salli].into_iter().map(|id| voice(id, LanguageCode::EnUs, &[])).collect();
+        assert_eq!(voice_ids(&build_speakers(voices, &[Engine::Neural], 1), "en"), ["Ivy"]);
+    }
+
+    #[test]
+    fn help_lists_every_request_field() {
+        let fields = serde_fields::struct_fields::<RequestData>();
+        for field in ["ipa", "language", "format", "strict_voice", "speech_marks"] {
+            assert!(fields.contains(&field), "{field} is missing");
+        }
+        assert_eq!(fields.len(), 25);
+    }
+
+    #[test]
+    fn best_engine_prefers_neural_then_standard() {
+        assert_eq!(speaker("Joanna", vec![Engine::Standard, Engine::Neural]).best_engine(), Engine::Neural);
+        assert_eq!(speaker("Ivy", vec![Engine::Standard]).best_engine(), Engine::Standard);
+    }
+
+    #[test]
+    fn best_engine_is_always_supported() {
+        let generative = Engine::from("generative");
+        assert_eq!(speaker("Ruth", vec![generative.clone()]).best_engine(), generative);
+        assert_eq!(speaker("Danielle", vec![generative, Engine::Neural]).best_engine(), Engine::Neural);
+    }
 }
\ No newline at end of file
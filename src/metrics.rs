@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::{Request, Response};
+
+use crate::emf::Emf;
+
+// Polly usually answers in well under a second, the top end is there for retries
+const LATENCY_BUCKETS: [f64; 8] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// Label values must come from small fixed sets (language names, engines, error codes),
+// never straight from the request, or the number of series grows without bound
+type Labels<'a> = &'a [(&'a str, &'a str)];
+
+#[derive(Default)]
+struct Counter(Mutex<BTreeMap<String, u64>>);
+
+impl Counter {
+    fn inc(&self, labels: Labels) {
+        *self.0.lock().unwrap().entry(render_labels(labels)).or_default() += 1;
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str) {
+        let _ = writeln!(output, "# HELP {name} {help}\n# TYPE {name} counter");
+        for (labels, value) in self.0.lock().unwrap().iter() {
+            let _ = writeln!(output, "{name}{} {value}", wrap_labels(labels));
+        }
+    }
+}
+
+#[derive(Default)]
+struct Gauge(AtomicU64);
+
+impl Gauge {
+    fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str) {
+        let _ = writeln!(output, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {}", self.0.load(Ordering::Relaxed));
+    }
+}
+
+#[derive(Default)]
+struct HistogramSeries {
+    bucket_counts: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Histogram(Mutex<BTreeMap<String, HistogramSeries>>);
+
+impl Histogram {
+    fn observe(&self, labels: Labels, value: f64) {
+        let mut series = self.0.lock().unwrap();
+        let series = series.entry(render_labels(labels)).or_default();
+        for (bucket, bound) in series.bucket_counts.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        series.sum += value;
+        series.count += 1;
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str) {
+        let _ = writeln!(output, "# HELP {name} {help}\n# TYPE {name} histogram");
+        for (labels, series) in self.0.lock().unwrap().iter() {
+            let separator = if labels.is_empty() { "" } else { "," };
+            for (count, bound) in series.bucket_counts.iter().zip(LATENCY_BUCKETS) {
+                let _ = writeln!(output, "{name}_bucket{{{labels}{separator}le=\"{bound}\"}} {count}");
+            }
+            let _ = writeln!(output, "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {}", series.count);
+            let _ = writeln!(output, "{name}_sum{} {}", wrap_labels(labels), series.sum);
+            let _ = writeln!(output, "{name}_count{} {}", wrap_labels(labels), series.count);
+        }
+    }
+}
+
+fn render_labels(labels: Labels) -> String {
+    labels.iter()
+        .map(|(name, value)| format!("{name}=\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn wrap_labels(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{labels}}}")
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    requests: Counter,
+    successes: Counter,
+    failures: Counter,
+    rate_limited: Counter,
+    polly_latency: Histogram,
+    cache_lookups: Counter,
+    cache_evictions: Counter,
+    cache_entries: Gauge,
+    // Written alongside the Prometheus ones, which /metrics keeps serving
+    emf: Option<Emf>,
+}
+
+impl Metrics {
+    pub fn from_env() -> Self {
+        Metrics { emf: Emf::from_env(), ..Default::default() }
+    }
+
+    fn emit(&self, name: &str, unit: &str, value: f64, dimensions: Labels) {
+        if let Some(emf) = &self.emf {
+            emf.emit(name, unit, value, dimensions);
+        }
+    }
+
+    pub fn record_request(&self) {
+        self.requests.inc(&[]);
+        self.emit("SynthesisRequests", "Count", 1.0, &[]);
+    }
+
+    pub fn record_success(&self, language: &str, engine: &str) {
+        let labels = [("language", language), ("engine", engine)];
+        self.successes.inc(&labels);
+        self.emit("SynthesisSuccesses", "Count", 1.0, &labels);
+    }
+
+    pub fn record_failure(&self, reason: &str) {
+        self.failures.inc(&[("reason", reason)]);
+        self.emit("SynthesisFailures", "Count", 1.0, &[("reason", reason)]);
+    }
+
+    pub fn record_polly_latency(&self, engine: &str, latency: Duration) {
+        self.polly_latency.observe(&[("engine", engine)], latency.as_secs_f64());
+        self.emit("PollyLatency", "Milliseconds", latency.as_secs_f64() * 1000.0, &[("engine", engine)]);
+    }
+
+    // cache is memory or s3
+    pub fn record_cache_lookup(&self, cache: &str, hit: bool) {
+        self.cache_lookups.inc(&[("cache", cache), ("result", if hit { "hit" } else { "miss" })]);
+    }
+
+    pub fn record_cache_put(&self, evicted: usize, entries: usize) {
+        for _ in 0..evicted {
+            self.cache_evictions.inc(&[]);
+        }
+        self.cache_entries.set(entries as u64);
+    }
+
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        self.requests.render(&mut output, "ipa_synthesis_requests_total", "Synthesis requests received");
+        self.successes.render(&mut output, "ipa_synthesis_successes_total", "Synthesis requests that returned audio");
+        self.failures.render(&mut output, "ipa_synthesis_failures_total", "Synthesis requests that failed, by error code");
+        self.rate_limited.render(&mut output, "ipa_rate_limited_total", "Requests rejected by the rate limiter");
+        self.polly_latency.render(&mut output, "ipa_polly_latency_seconds", "Time spent waiting on Polly, retries included");
+        self.cache_lookups.render(&mut output, "ipa_cache_lookups_total", "Cache lookups by cache and whether they hit");
+        self.cache_evictions.render(&mut output, "ipa_cache_evictions_total", "Clips evicted from the memory cache to make room");
+        self.cache_entries.render(&mut output, "ipa_cache_entries", "Clips in the memory cache");
+
+        output
+    }
+}
+
+// The governor rejects requests before they reach a handler, so the only place to see them is the response
+pub struct RateLimitCounter;
+
+#[rocket::async_trait]
+impl Fairing for RateLimitCounter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Count rate limited requests",
+            kind: Kind::Response
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if response.status() == Status::TooManyRequests {
+            if let Some(metrics) = request.rocket().state::<Metrics>() {
+                metrics.rate_limited.inc(&[]);
+                metrics.emit("RateLimited", "Count", 1.0, &[]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_lookups_are_counted_by_result() {
+        let metrics = Metrics::default();
+        metrics.record_cache_lookup("memory", true);
+        metrics.record_cache_lookup("memory", false);
+        metrics.record_cache_lookup("memory", false);
+        metrics.record_cache_put(1, 30);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("# TYPE ipa_cache_lookups_total counter\n"), "{rendered}");
+        assert!(rendered.contains("\nipa_cache_lookups_total{cache=\"memory\",result=\"hit\"} 1\n"), "{rendered}");
+        assert!(rendered.contains("\nipa_cache_lookups_total{cache=\"memory\",result=\"miss\"} 2\n"), "{rendered}");
+        assert!(rendered.contains("\nipa_cache_evictions_total 1\n"), "{rendered}");
+        assert!(rendered.contains("\nipa_cache_entries 30\n"), "{rendered}");
+    }
+
+    #[test]
+    fn latency_lands_in_every_bucket_above_it() {
+        let metrics = Metrics::default();
+        metrics.polly_latency.observe(&[("engine", "neural")], 0.3);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("ipa_polly_latency_seconds_bucket{engine=\"neural\",le=\"0.25\"} 0\n"), "{rendered}");
+        assert!(rendered.contains("ipa_polly_latency_seconds_bucket{engine=\"neural\",le=\"0.5\"} 1\n"), "{rendered}");
+        assert!(rendered.contains("ipa_polly_latency_seconds_bucket{engine=\"neural\",le=\"+Inf\"} 1\n"), "{rendered}");
+        assert!(rendered.contains("ipa_polly_latency_seconds_count{engine=\"neural\"} 1\n"), "{rendered}");
+    }
+}
\ No newline at end of file
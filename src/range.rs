@@ -0,0 +1,101 @@
+// A single byte range out of a Range header, clamped to the body. Several ranges at once aren't
+// worth a multipart response for a clip this size, so those get the whole body same as no Range.
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    // Both inclusive
+    Satisfiable { start: usize, end: usize },
+    Unsatisfiable,
+}
+
+// None when the header should be ignored, which RFC 9110 says to do with anything malformed
+pub fn parse(header: &str, length: usize) -> Option<ByteRange> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    // bytes=-500 is the last 500
+    if start.is_empty() {
+        let suffix_length: usize = end.parse().ok()?;
+        if suffix_length == 0 || length == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        return Some(ByteRange::Satisfiable { start: length.saturating_sub(suffix_length), end: length - 1 });
+    }
+
+    let start: usize = start.parse().ok()?;
+    let end = match end {
+        "" => None,
+        end => Some(end.parse::<usize>().ok()?),
+    };
+    if end.is_some_and(|end| end < start) {
+        return None;
+    }
+    if start >= length {
+        return Some(ByteRange::Unsatisfiable);
+    }
+
+    Some(ByteRange::Satisfiable { start, end: end.map_or(length - 1, |end| end.min(length - 1)) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn satisfiable(start: usize, end: usize) -> Option<ByteRange> {
+        Some(ByteRange::Satisfiable { start, end })
+    }
+
+    #[test]
+    fn ranges_are_clamped_to_the_body() {
+        assert_eq!(parse("bytes=0-99", 1000), satisfiable(0, 99));
+        assert_eq!(parse("bytes=900-1999", 1000), satisfiable(900, 999));
+        assert_eq!(parse("bytes=5-5", 1000), satisfiable(5, 5));
+        assert_eq!(parse(" bytes= 10 - 20 ", 1000), satisfiable(10, 20));
+    }
+
+    #[test]
+    fn open_ended_goes_to_the_end() {
+        assert_eq!(parse("bytes=500-", 1000), satisfiable(500, 999));
+        assert_eq!(parse("bytes=0-", 1), satisfiable(0, 0));
+    }
+
+    #[test]
+    fn suffix_is_the_last_bytes() {
+        assert_eq!(parse("bytes=-100", 1000), satisfiable(900, 999));
+        // Longer than the body is all of it
+        assert_eq!(parse("bytes=-5000", 1000), satisfiable(0, 999));
+        assert_eq!(parse("bytes=-0", 1000), Some(ByteRange::Unsatisfiable));
+    }
+
+    #[test]
+    fn starting_past_the_end_is_unsatisfiable() {
+        assert_eq!(parse("bytes=1000-", 1000), Some(ByteRange::Unsatisfiable));
+        assert_eq!(parse("bytes=1000-1100", 1000), Some(ByteRange::Unsatisfiable));
+        assert_eq!(parse("bytes=5000-", 1000), Some(ByteRange::Unsatisfiable));
+    }
+
+    #[test]
+    fn empty_body_has_nothing_to_give() {
+        assert_eq!(parse("bytes=0-", 0), Some(ByteRange::Unsatisfiable));
+        assert_eq!(parse("bytes=0-10", 0), Some(ByteRange::Unsatisfiable));
+        assert_eq!(parse("bytes=-10", 0), Some(ByteRange::Unsatisfiable));
+    }
+
+    #[test]
+    fn anything_else_is_ignored() {
+        // End before start
+        assert_eq!(parse("bytes=20-10", 1000), None);
+        // Several ranges
+        assert_eq!(parse("bytes=0-10,20-30", 1000), None);
+        assert_eq!(parse("bytes=0-10, 20-", 1000), None);
+        // Malformed
+        assert_eq!(parse("bytes=-", 1000), None);
+        assert_eq!(parse("bytes=a-b", 1000), None);
+        assert_eq!(parse("bytes=10", 1000), None);
+        assert_eq!(parse("items=0-10", 1000), None);
+        assert_eq!(parse("bytes=-1-2", 1000), None);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
+
+use governor::clock::{Clock, DefaultClock};
+use governor::middleware::StateInformationMiddleware;
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::{Quota, RateLimiter};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromRequest};
+use rocket::serde::Serialize;
+use rocket::{Request, Response};
+
+use crate::auth::ApiKey;
+use crate::config;
+use crate::error::ApiError;
+use crate::ip_filter::{self, IpRange};
+
+const DEFAULT_RATE_LIMIT_PER_HOUR: u32 = 100;
+// Past this many, clients whose allowance has refilled completely get forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+// Every API key gets its own allowance, requests without one share theirs with the rest of their IP
+pub struct RateLimits {
+    limiter: RateLimiter<String, DefaultKeyedStateStore<String>, DefaultClock, StateInformationMiddleware>,
+    per_hour: NonZeroU32,
+    clock: DefaultClock,
+    // Remaining allowance at each client's last request. Governor can't be asked without using some
+    // up, so GET /rate-limit works forwards from this instead.
+    last_seen: Mutex<HashMap<String, (u32, Instant)>>,
+    // Our own callers, never limited whether or not they send a key. Behind a lock so POST /admin/reload can re-read the file.
+    exempt: RwLock<Vec<IpRange>>,
+}
+
+fn exempt_from_env() -> Vec<IpRange> {
+    ip_filter::load_ranges("IPA_RATE_LIMIT_EXEMPT", "IPA_RATE_LIMIT_EXEMPT_FILE")
+}
+
+impl RateLimits {
+    pub fn from_env() -> Self {
+        let per_hour = NonZeroU32::new(config::env_or("IPA_RATE_LIMIT_PER_HOUR", DEFAULT_RATE_LIMIT_PER_HOUR)).unwrap_or(NonZeroU32::new(DEFAULT_RATE_LIMIT_PER_HOUR).unwrap());
+        RateLimits {
+            limiter: RateLimiter::keyed(Quota::per_hour(per_hour)).with_middleware::<StateInformationMiddleware>(),
+            per_hour,
+            clock: DefaultClock::default(),
+            last_seen: Mutex::new(HashMap::new()),
+            exempt: RwLock::new(exempt_from_env()),
+        }
+    }
+
+    // Only the exemptions, they're the part that can come from a file. The quota is only ever
+    // IPA_RATE_LIMIT_PER_HOUR, and a running process never sees its environment change, so a new
+    // quota needs a restart. Returns whether the exemptions are any different.
+    pub fn reload(&self) -> bool {
+        let exempt = exempt_from_env();
+        let mut current = self.exempt.write().unwrap();
+        let changed = *current != exempt;
+        *current = exempt;
+        changed
+    }
+
+    pub fn per_hour(&self) -> NonZeroU32 {
+        self.per_hour
+    }
+
+    pub fn exempts(&self, ip: Option<IpAddr>) -> bool {
+        ip.is_some_and(|ip| self.exempt.read().unwrap().iter().any(|range| range.contains(ip)))
+    }
+
+    // Uses up one request from the client's allowance, returning what's left or how many seconds until they can try again
+    pub fn check(&self, client: String) -> Result<u32, u64> {
+        if self.limiter.len() > MAX_TRACKED_CLIENTS {
+            self.limiter.retain_recent();
+        }
+
+        match self.limiter.check_key(&client) {
+            Ok(snapshot) => {
+                self.remember(client, snapshot.remaining_burst_capacity());
+                Ok(snapshot.remaining_burst_capacity())
+            }
+            Err(not_until) => {
+                self.remember(client, 0);
+                Err(not_until.wait_time_from(self.clock.now()).as_secs().max(1))
+            }
+        }
+    }
+
+    fn remember(&self, client: String, remaining: u32) {
+        let mut last_seen = self.last_seen.lock().unwrap();
+        if last_seen.len() > MAX_TRACKED_CLIENTS {
+            // Anyone from over an hour ago is back to a full allowance anyway
+            last_seen.retain(|_, (_, seen)| seen.elapsed().as_secs() < 60 * 60);
+        }
+        last_seen.insert(client, (remaining, Instant::now()));
+    }
+
+    // Allowance refills evenly over the hour, same as governor's
+    fn estimate_remaining(&self, client: &str) -> u32 {
+        let per_hour = self.per_hour().get();
+        match self.last_seen.lock().unwrap().get(client) {
+            Some((remaining, seen)) => {
+                let refilled = (seen.elapsed().as_secs_f64() * f64::from(per_hour) / (60.0 * 60.0)) as u32;
+                remaining.saturating_add(refilled).min(per_hour)
+            }
+            None => per_hour,
+        }
+    }
+}
+
+// Requests with a key are counted against the key, the rest against their IP
+pub fn client_id(api_key: Option<&ApiKey>, ip: Option<IpAddr>) -> String {
+    match api_key {
+        Some(ApiKey(Some(api_key))) => format!("key:{api_key}"),
+        _ => format!("ip:{}", ip.map_or("unknown".to_string(), |ip| ip.to_string())),
+    }
+}
+
+async fn request_client_id(request: &Request<'_>) -> String {
+    client_id(request.guard::<ApiKey>().await.succeeded().as_ref(), request.client_ip())
+}
+
+// Left for RateLimitHeaders to report
+enum LimitState {
+    Allowed { remaining: u32 },
+    Limited { retry_after_secs: u64 },
+}
+
+pub struct RateLimit;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimit {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(rate_limits) = request.rocket().state::<RateLimits>() else {
+            return request::Outcome::Success(RateLimit);
+        };
+        // No headers either, there's no allowance to report
+        if rate_limits.exempts(request.client_ip()) {
+            return request::Outcome::Success(RateLimit);
+        }
+
+        match rate_limits.check(request_client_id(request).await) {
+            Ok(remaining) => {
+                request.local_cache(|| Some(LimitState::Allowed { remaining }));
+                request::Outcome::Success(RateLimit)
+            }
+            Err(retry_after_secs) => {
+                request.local_cache(|| Some(LimitState::Limited { retry_after_secs }));
+                request::Outcome::Error((Status::TooManyRequests, ()))
+            }
+        }
+    }
+}
+
+// The caller's allowance without using any of it up. An estimate, see RateLimits::last_seen.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RateLimitStatus {
+    limit: u32,
+    remaining: u32,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimitStatus {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(rate_limits) = request.rocket().state::<RateLimits>() else {
+            return request::Outcome::Forward(Status::InternalServerError);
+        };
+
+        let client = request_client_id(request).await;
+        request::Outcome::Success(RateLimitStatus {
+            limit: rate_limits.per_hour().get(),
+            remaining: rate_limits.estimate_remaining(&client),
+        })
+    }
+}
+
+#[catch(429)]
+pub fn too_many_requests_catcher(request: &Request) -> ApiError {
+    match request.local_cache(|| None::<LimitState>) {
+        Some(LimitState::Limited { retry_after_secs }) => {
+            ApiError::new(Status::TooManyRequests, "rate_limited", format!("Rate limit exceeded, try again in {retry_after_secs}s")).with_retry_after(*retry_after_secs)
+        }
+        _ => ApiError::new(Status::TooManyRequests, "rate_limited", "Rate limit exceeded".to_string()),
+    }
+}
+
+pub struct RateLimitHeaders;
+
+#[rocket::async_trait]
+impl Fairing for RateLimitHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit headers",
+            kind: Kind::Response
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(rate_limits) = request.rocket().state::<RateLimits>() else { return };
+
+        match request.local_cache(|| None::<LimitState>) {
+            Some(LimitState::Allowed { remaining }) => {
+                response.set_header(Header::new("X-RateLimit-Limit", rate_limits.per_hour().to_string()));
+                response.set_header(Header::new("X-RateLimit-Remaining", remaining.to_string()));
+            }
+            Some(LimitState::Limited { retry_after_secs }) => {
+                response.set_header(Header::new("X-RateLimit-Limit", rate_limits.per_hour().to_string()));
+                response.set_header(Header::new("X-RateLimit-Remaining", "0"));
+                response.set_header(Header::new("Retry-After", retry_after_secs.to_string()));
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn limits_with(variables: &[(&str, &str)]) -> RateLimits {
+        let _env = crate::config::lock_env();
+        for (name, value) in variables {
+            env::set_var(name, value);
+        }
+        let limits = RateLimits::from_env();
+        for (name, _) in variables {
+            env::remove_var(name);
+        }
+
+        limits
+    }
+
+    #[test]
+    fn quota_comes_from_the_environment() {
+        let limits = limits_with(&[("IPA_RATE_LIMIT_PER_HOUR", "3")]);
+        assert_eq!(limits.per_hour().get(), 3);
+        assert_eq!(limits.check("ip:192.0.2.1".to_string()), Ok(2));
+        assert_eq!(limits.check("ip:192.0.2.1".to_string()), Ok(1));
+        assert_eq!(limits.check("ip:192.0.2.1".to_string()), Ok(0));
+        assert!(limits.check("ip:192.0.2.1".to_string()).is_err());
+        // Everyone else still has theirs
+        assert_eq!(limits.check("ip:192.0.2.2".to_string()), Ok(2));
+    }
+
+    #[get("/")]
+    fn limited(_rate_limit: RateLimit) -> &'static str {
+        "hello"
+    }
+
+    #[test]
+    fn exempt_ranges_match_by_ip() {
+        let limits = limits_with(&[("IPA_RATE_LIMIT_EXEMPT", "10.0.0.0/8, 192.0.2.7")]);
+        assert!(limits.exempts(Some("10.1.2.3".parse().unwrap())));
+        assert!(limits.exempts(Some("192.0.2.7".parse().unwrap())));
+        assert!(!limits.exempts(Some("192.0.2.8".parse().unwrap())));
+        assert!(!limits.exempts(Some("11.0.0.1".parse().unwrap())));
+        assert!(!limits.exempts(None));
+    }
+
+    #[test]
+    fn exempt_ip_is_never_limited() {
+        use rocket::local::blocking::Client;
+
+        let limits = limits_with(&[("IPA_RATE_LIMIT_PER_HOUR", "1"), ("IPA_RATE_LIMIT_EXEMPT", "10.0.0.0/8")]);
+        let rocket = rocket::build()
+            .mount("/", routes![limited])
+            .register("/", catchers![too_many_requests_catcher])
+            .manage(limits)
+            .attach(RateLimitHeaders);
+        let client = Client::tracked(rocket).unwrap();
+        let from = |ip: &str| client.get("/").remote(format!("{ip}:1234").parse().unwrap()).dispatch();
+
+        for _ in 0..5 {
+            let response = from("10.0.0.5");
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.headers().get_one("X-RateLimit-Limit"), None);
+        }
+
+        let response = from("192.0.2.1");
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("X-RateLimit-Remaining"), Some("0"));
+        let response = from("192.0.2.1");
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert!(response.headers().get_one("Retry-After").is_some());
+    }
+
+    #[test]
+    fn bad_quota_falls_back_to_the_default() {
+        for value in ["", "0", "lots", "-5"] {
+            assert_eq!(limits_with(&[("IPA_RATE_LIMIT_PER_HOUR", value)]).per_hour().get(), DEFAULT_RATE_LIMIT_PER_HOUR, "{value:?}");
+        }
+    }
+    #[test]
+    fn reload_rereads_the_exemptions_but_keeps_the_quota() {
+        let _env = crate::config::lock_env();
+        let path = env::temp_dir().join(format!("ipa_server_exempt_{}", std::process::id()));
+        std::fs::write(&path, "192.0.2.1\n").unwrap();
+        env::set_var("IPA_RATE_LIMIT_PER_HOUR", "3");
+        env::set_var("IPA_RATE_LIMIT_EXEMPT_FILE", &path);
+        let limits = RateLimits::from_env();
+        assert!(limits.exempts(Some("192.0.2.1".parse().unwrap())));
+        assert!(!limits.reload());
+
+        std::fs::write(&path, "# moved\n192.0.2.2\n").unwrap();
+        env::set_var("IPA_RATE_LIMIT_PER_HOUR", "50");
+        let changed = limits.reload();
+        env::remove_var("IPA_RATE_LIMIT_PER_HOUR");
+        env::remove_var("IPA_RATE_LIMIT_EXEMPT_FILE");
+        let _ = std::fs::remove_file(&path);
+
+        assert!(changed);
+        assert!(!limits.exempts(Some("192.0.2.1".parse().unwrap())));
+        assert!(limits.exempts(Some("192.0.2.2".parse().unwrap())));
+        assert_eq!(limits.per_hour().get(), 3);
+    }
+}
\ No newline at end of file
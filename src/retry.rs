@@ -0,0 +1,155 @@
+use std::future::Future;
+use std::time::Duration;
+
+use aws_sdk_polly::error::SynthesizeSpeechError;
+use aws_sdk_polly::types::SdkError;
+use rand::Rng;
+
+pub const MAX_ATTEMPTS: u32 = 3;
+const BASE_DELAY_MS: u64 = 100;
+
+// Throttling, 5xx and connection trouble are worth another go. Anything else (bad SSML,
+// unknown voice, ...) will fail the same way every time.
+pub fn is_retryable(err: &SdkError<SynthesizeSpeechError>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::ResponseError { .. } => true,
+        SdkError::DispatchFailure(connector_error) => connector_error.is_io() || connector_error.is_timeout(),
+        SdkError::ServiceError { err: service_err, raw } => {
+            service_err.is_service_failure_exception() || is_throttled(err) || raw.http().status().is_server_error()
+        }
+        SdkError::ConstructionFailure(_) => false,
+    }
+}
+
+// Polly or the account's limit pushing back, rather than anything being broken
+pub fn is_throttled(err: &SdkError<SynthesizeSpeechError>) -> bool {
+    match err {
+        SdkError::ServiceError { err, raw } => {
+            matches!(err.code(), Some("ThrottlingException" | "Throttling" | "TooManyRequestsException"))
+                || raw.http().status().as_u16() == 429
+        }
+        _ => false,
+    }
+}
+
+// Exponential with up to as much again in jitter, so retries from concurrent requests spread out
+pub fn backoff(attempt: u32) -> Duration {
+    let delay_ms = BASE_DELAY_MS * 2u64.pow(attempt - 1);
+    let jitter_ms = rand::thread_rng().gen_range(0..=delay_ms);
+
+    Duration::from_millis(delay_ms + jitter_ms)
+}
+
+// Calls send until it succeeds, fails in a way another go won't fix, or has had MAX_ATTEMPTS
+pub async fn with_retries<T, F, Fut>(mut send: F) -> Result<T, SdkError<SynthesizeSpeechError>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SdkError<SynthesizeSpeechError>>>,
+{
+    let mut attempt = 1;
+    loop {
+        match send().await {
+            Err(err) if attempt < MAX_ATTEMPTS && is_retryable(&err) => {
+                let delay = backoff(attempt);
+                warn!("Synthesis attempt {attempt} failed, retrying in {}ms: {err}", delay.as_millis());
+                rocket::tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use aws_sdk_polly::error::{ServiceFailureException, SynthesizeSpeechErrorKind};
+    use aws_smithy_http::body::SdkBody;
+    use aws_smithy_http::operation;
+
+    use super::*;
+
+    fn service_error(status: u16, code: &str) -> SdkError<SynthesizeSpeechError> {
+        let meta = aws_smithy_types::Error::builder().code(code).build();
+        let err = match code {
+            "ServiceFailureException" => SynthesizeSpeechError::new(SynthesizeSpeechErrorKind::ServiceFailureException(ServiceFailureException::builder().build()), meta),
+            _ => SynthesizeSpeechError::generic(meta),
+        };
+        let raw = operation::Response::new(http::Response::builder().status(status).body(SdkBody::empty()).unwrap());
+
+        SdkError::ServiceError { err, raw }
+    }
+
+    fn timeout() -> SdkError<SynthesizeSpeechError> {
+        SdkError::TimeoutError("timed out".into())
+    }
+
+    #[test]
+    fn retries_only_what_might_work_next_time() {
+        assert!(is_retryable(&timeout()));
+        assert!(is_retryable(&service_error(500, "ServiceFailureException")));
+        assert!(is_retryable(&service_error(503, "Unknown")));
+        assert!(is_retryable(&service_error(400, "ThrottlingException")));
+        assert!(is_retryable(&service_error(429, "Unknown")));
+        assert!(!is_retryable(&service_error(400, "InvalidSsmlException")));
+        assert!(!is_retryable(&SdkError::ConstructionFailure("bad input".into())));
+    }
+
+    #[test]
+    fn throttling_is_by_code_or_429() {
+        assert!(is_throttled(&service_error(400, "TooManyRequestsException")));
+        assert!(is_throttled(&service_error(429, "Unknown")));
+        assert!(!is_throttled(&service_error(500, "ServiceFailureException")));
+        assert!(!is_throttled(&timeout()));
+    }
+
+    #[test]
+    fn backoff_doubles_with_up_to_as_much_jitter() {
+        for attempt in 1..=MAX_ATTEMPTS {
+            let delay_ms = BASE_DELAY_MS * 2u64.pow(attempt - 1);
+            for _ in 0..100 {
+                let backoff = backoff(attempt).as_millis() as u64;
+                assert!((delay_ms..=delay_ms * 2).contains(&backoff), "attempt {attempt} waited {backoff}ms");
+            }
+        }
+    }
+
+    #[rocket::async_test]
+    async fn succeeds_after_two_timeouts() {
+        let calls = AtomicU32::new(0);
+        let result = with_retries(|| async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(timeout()),
+                _ => Ok("audio"),
+            }
+        }).await;
+
+        assert_eq!(result.ok(), Some("audio"));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[rocket::async_test]
+    async fn gives_up_after_max_attempts() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = with_retries(|| async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(service_error(503, "Unknown"))
+        }).await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), MAX_ATTEMPTS);
+    }
+
+    #[rocket::async_test]
+    async fn does_not_retry_a_bad_request() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = with_retries(|| async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(service_error(400, "InvalidSsmlException"))
+        }).await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
\ No newline at end of file
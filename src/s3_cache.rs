@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use aws_sdk_s3::types::{ByteStream, SdkError};
+use aws_sdk_s3::Client;
+use bytes::Bytes;
+
+// A slow bucket shouldn't eat into the time Polly gets
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Shared between every instance pointed at the same bucket. Anything going wrong with S3
+// just means synthesizing again, it never fails the request.
+#[derive(Clone)]
+pub struct S3Cache {
+    client: Client,
+    bucket: String,
+}
+
+impl S3Cache {
+    pub fn new(client: Client, bucket: String) -> Self {
+        S3Cache { client, bucket }
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Bytes> {
+        let lookup = self.client.get_object().bucket(&self.bucket).key(key).send();
+        let object = match rocket::tokio::time::timeout(LOOKUP_TIMEOUT, lookup).await {
+            Ok(Ok(object)) => object,
+            Ok(Err(SdkError::ServiceError { err, .. })) if err.is_no_such_key() => return None,
+            Ok(Err(err)) => {
+                warn!("Failed to read {key} from the S3 cache: {err}");
+                return None;
+            }
+            Err(_) => {
+                warn!("Timed out reading {key} from the S3 cache");
+                return None;
+            }
+        };
+
+        match object.body.collect().await {
+            Ok(audio) => Some(audio.into_bytes()),
+            Err(err) => {
+                warn!("Failed to read {key} from the S3 cache: {err}");
+                None
+            }
+        }
+    }
+
+    // Uploads in the background so the response doesn't wait on it
+    pub fn put(&self, key: String, audio: Bytes) {
+        let cache = self.clone();
+        rocket::tokio::spawn(async move {
+            if let Err(err) = cache.client.put_object().bucket(&cache.bucket).key(&key).body(ByteStream::from(audio)).send().await {
+                warn!("Failed to upload {key} to the S3 cache: {err}");
+            }
+        });
+    }
+}
\ No newline at end of file
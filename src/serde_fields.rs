@@ -0,0 +1,74 @@
+use std::fmt;
+
+use rocket::serde::de::{self, Visitor};
+use rocket::serde::{forward_to_deserialize_any, Deserialize, Deserializer};
+
+// The field names a struct's derived Deserialize expects, as serde itself knows them. Anything that
+// isn't a struct comes back empty.
+pub fn struct_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+// Only ever asked for a struct, and bails out as soon as it's been told the fields
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+#[derive(Debug)]
+struct Stop;
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "only collecting field names")
+    }
+}
+
+impl std::error::Error for Stop {}
+
+impl de::Error for Stop {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        Stop
+    }
+}
+
+impl<'de> Deserializer<'de> for FieldNames<'_> {
+    type Error = Stop;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Stop> {
+        Err(Stop)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], _visitor: V) -> Result<V::Value, Stop> {
+        *self.0 = fields;
+        Err(Stop)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[serde(crate = "rocket::serde")]
+    #[allow(dead_code)]
+    struct Example {
+        first: String,
+        #[serde(rename = "renamed")]
+        second: Option<u64>,
+    }
+
+    #[test]
+    fn lists_serde_field_names() {
+        assert_eq!(struct_fields::<Example>(), ["first", "renamed"]);
+    }
+
+    #[test]
+    fn non_structs_have_no_fields() {
+        assert!(struct_fields::<String>().is_empty());
+    }
+}
\ No newline at end of file
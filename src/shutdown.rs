@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Orbit, Request, Rocket};
+
+use crate::config;
+
+// Rocket's default is 2s, not enough for a slow synthesis to finish
+const DEFAULT_SHUTDOWN_GRACE_SECS: u32 = 10;
+
+pub fn grace_secs() -> u32 {
+    config::env_or("IPA_SHUTDOWN_GRACE_SECS", DEFAULT_SHUTDOWN_GRACE_SECS)
+}
+
+// Dropped along with the request, which Rocket keeps around until the whole body is written
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Rocket does the actual draining, this just says what's going on while it does
+pub struct ShutdownLogger {
+    grace: Duration,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl ShutdownLogger {
+    pub fn new(grace_secs: u32) -> Self {
+        ShutdownLogger {
+            grace: Duration::from_secs(grace_secs.into()),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for ShutdownLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Log graceful shutdown",
+            kind: Kind::Request | Kind::Shutdown
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        request.local_cache(|| Some(InFlightGuard(self.in_flight.clone())));
+    }
+
+    async fn on_shutdown(&self, _rocket: &Rocket<Orbit>) {
+        info!("Shutting down, no longer accepting connections. Waiting up to {}s for {} requests in flight", self.grace.as_secs(), self.in_flight.load(Ordering::SeqCst));
+
+        // Not awaited, the grace period is already running and Rocket waits for shutdown fairings before acting on it
+        let (grace, in_flight) = (self.grace, self.in_flight.clone());
+        rocket::tokio::spawn(async move {
+            let started = Instant::now();
+            while started.elapsed() < grace {
+                if in_flight.load(Ordering::SeqCst) == 0 {
+                    info!("All requests finished, shutting down");
+                    return;
+                }
+                rocket::tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+
+            warn!("Grace period over with {} requests still in flight, their audio will be cut off", in_flight.load(Ordering::SeqCst));
+        });
+    }
+}
\ No newline at end of file
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Mutex;
+
+use futures::channel::oneshot;
+use futures::future::{FutureExt, Shared};
+
+// Concurrent calls for the same key wait on whichever got there first instead of all doing the
+// same work. Nothing is kept once it's finished, that's what the caches are for.
+pub struct SingleFlight<K, V> {
+    in_flight: Mutex<HashMap<K, Shared<oneshot::Receiver<V>>>>,
+}
+
+enum Role<V> {
+    Leader(oneshot::Sender<V>),
+    Follower(Shared<oneshot::Receiver<V>>),
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> SingleFlight<K, V> {
+    pub fn new() -> Self {
+        SingleFlight { in_flight: Mutex::new(HashMap::new()) }
+    }
+
+    pub async fn run<F: Future<Output = V>>(&self, key: K, work: F) -> V {
+        loop {
+            let role = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match in_flight.get(&key) {
+                    Some(pending) => Role::Follower(pending.clone()),
+                    None => {
+                        let (sender, receiver) = oneshot::channel();
+                        in_flight.insert(key.clone(), receiver.shared());
+                        Role::Leader(sender)
+                    }
+                }
+            };
+
+            match role {
+                Role::Leader(sender) => {
+                    // Removed even if the leader's request is dropped halfway, so the key isn't stuck
+                    let _entry = InFlightEntry { in_flight: &self.in_flight, key };
+                    let value = work.await;
+                    let _ = sender.send(value.clone());
+                    return value;
+                }
+                Role::Follower(pending) => {
+                    if let Ok(value) = pending.await {
+                        return value;
+                    }
+                    // The leader went away before finishing. Its entry is gone by now, so the first
+                    // follower back takes over and the rest wait on that one.
+                }
+            }
+        }
+    }
+}
+
+struct InFlightEntry<'a, K: Eq + Hash, V> {
+    in_flight: &'a Mutex<HashMap<K, Shared<oneshot::Receiver<V>>>>,
+    key: K,
+}
+
+impl<K: Eq + Hash, V> Drop for InFlightEntry<'_, K, V> {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures::future::{join, join_all};
+    use rocket::tokio::time::{sleep, timeout};
+
+    use super::*;
+
+    async fn slow_work(calls: &AtomicUsize, delay: Duration) -> usize {
+        let call = calls.fetch_add(1, Ordering::SeqCst);
+        sleep(delay).await;
+        call
+    }
+
+    #[rocket::async_test]
+    async fn concurrent_runs_share_one_piece_of_work() {
+        let flight = SingleFlight::new();
+        let calls = AtomicUsize::new(0);
+        let results = join_all((0..10).map(|_| flight.run("hello", slow_work(&calls, Duration::from_millis(50))))).await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(results.iter().all(|result| *result == 0));
+        assert!(flight.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn different_keys_run_separately() {
+        let flight = SingleFlight::new();
+        let calls = AtomicUsize::new(0);
+        join(flight.run("hello", slow_work(&calls, Duration::from_millis(10))), flight.run("goodbye", slow_work(&calls, Duration::from_millis(10)))).await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[rocket::async_test]
+    async fn followers_take_over_from_a_dropped_leader() {
+        let flight = SingleFlight::new();
+        let leader_calls = AtomicUsize::new(0);
+        let follower_calls = AtomicUsize::new(0);
+        // The leader gives up long before its work would finish, the way a disconnected client's request does
+        let leader = timeout(Duration::from_millis(20), flight.run("hello", slow_work(&leader_calls, Duration::from_secs(60))));
+        let followers = join_all((0..10).map(|_| flight.run("hello", slow_work(&follower_calls, Duration::from_millis(50)))));
+        let (leader, results) = join(leader, followers).await;
+
+        assert!(leader.is_err());
+        assert_eq!(leader_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(follower_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(results.len(), 10);
+        assert!(flight.in_flight.lock().unwrap().is_empty());
+    }
+}
\ No newline at end of file
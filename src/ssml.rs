@@ -0,0 +1,412 @@
+// Makes user input safe to put inside SSML text or a quoted attribute
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '\'' => escaped.push_str("&apos;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(character),
+        }
+    }
+
+    escaped
+}
+
+// Anything longer and the client is better off making separate requests
+pub const MAX_BREAK_MS: u64 = 2000;
+
+// drc is dynamic range compression, for listening somewhere noisy
+pub const EFFECT_NAMES: [&str; 2] = ["whispered", "drc"];
+
+pub struct SsmlParams<'a> {
+    pub alphabet: &'a str,
+    pub words: &'a [&'a str],
+    // Pause between words, capped at MAX_BREAK_MS
+    pub break_ms: Option<u64>,
+    pub language_code: Option<&'a str>,
+    // Sentence to say the phonemes in, with {} where they go. Checked to have exactly one.
+    pub carrier: Option<&'a str>,
+    // One of EFFECT_NAMES
+    pub effect: Option<&'a str>,
+    pub prosody: &'a Prosody<'a>,
+}
+
+// Everything that goes to Polly is built here, so user input only reaches the SSML escaped.
+// The prosody has to have passed validate() first.
+pub fn build_ssml(params: &SsmlParams) -> String {
+    let phonemes = phonemes(params.alphabet, params.words, params.break_ms);
+    // In a sentence the voice uses its normal intonation, on their own words tend to come out flat
+    let phonemes = match params.carrier.and_then(|carrier| carrier.split_once("{}")) {
+        Some((before, after)) => format!("{}{phonemes}{}", escape(before), escape(after)),
+        None => phonemes,
+    };
+    let content = match params.language_code {
+        Some(language_code) => lang(language_code, &phonemes),
+        None => phonemes,
+    };
+    let content = match params.effect {
+        Some(effect) => format!("<amazon:effect name='{}'>{content}</amazon:effect>", escape(effect)),
+        None => content,
+    };
+
+    params.prosody.wrap(&content)
+}
+
+// One <phoneme> per word. Polly runs everything in a single phoneme together as one long word.
+// Without break_ms the words are only spaced apart, which Polly reads at its normal pace.
+fn phonemes(alphabet: &str, words: &[&str], break_ms: Option<u64>) -> String {
+    let separator = match break_ms {
+        Some(break_ms) => format!("<break time='{}ms'/>", break_ms.min(MAX_BREAK_MS)),
+        None => " ".to_string(),
+    };
+
+    words.iter()
+        .map(|word| format!("<phoneme alphabet='{}' ph='{}'></phoneme>", escape(alphabet), escape(word)))
+        .collect::<Vec<_>>()
+        .join(&separator)
+}
+
+// Says which language the phonemes are in, for multilingual voices that would otherwise
+// read them as their own default language
+fn lang(language_code: &str, content: &str) -> String {
+    format!("<lang xml:lang='{}'>{content}</lang>", escape(language_code))
+}
+
+const RATE_KEYWORDS: [&str; 5] = ["x-slow", "slow", "medium", "fast", "x-fast"];
+const PITCH_KEYWORDS: [&str; 6] = ["default", "x-low", "low", "medium", "high", "x-high"];
+const VOLUME_KEYWORDS: [&str; 7] = ["default", "silent", "x-soft", "soft", "medium", "loud", "x-loud"];
+
+// Polly won't go below 20% or above 200% of the default rate
+const MIN_RATE_PERCENT: f64 = 20.0;
+const MAX_RATE_PERCENT: f64 = 200.0;
+
+#[derive(Default)]
+pub struct Prosody<'a> {
+    pub rate: Option<&'a str>,
+    pub pitch: Option<&'a str>,
+    pub volume: Option<&'a str>,
+}
+
+impl Prosody<'_> {
+    // On failure returns the attribute and the value it didn't like
+    pub fn validate(&self) -> Result<(), (&'static str, &str)> {
+        if let Some(rate) = self.rate {
+            let valid = RATE_KEYWORDS.contains(&rate) || parse_number(rate, "%", false)
+                .is_some_and(|percent| (MIN_RATE_PERCENT..=MAX_RATE_PERCENT).contains(&percent));
+            if !valid {
+                return Err(("rate", rate));
+            }
+        }
+        if let Some(pitch) = self.pitch {
+            if !PITCH_KEYWORDS.contains(&pitch) && parse_number(pitch, "%", true).is_none() {
+                return Err(("pitch", pitch));
+            }
+        }
+        if let Some(volume) = self.volume {
+            if !VOLUME_KEYWORDS.contains(&volume) && parse_number(volume, "dB", true).is_none() {
+                return Err(("volume", volume));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rate.is_none() && self.pitch.is_none() && self.volume.is_none()
+    }
+
+    // Assumes validate() passed, so values don't need escaping
+    pub fn wrap(&self, content: &str) -> String {
+        if self.is_empty() {
+            return content.to_string();
+        }
+
+        let mut attributes = String::new();
+        for (name, value) in [("rate", self.rate), ("pitch", self.pitch), ("volume", self.volume)] {
+            if let Some(value) = value {
+                attributes.push_str(&format!(" {name}='{value}'"));
+            }
+        }
+
+        format!("<prosody{attributes}>{content}</prosody>")
+    }
+}
+
+// Parses things like "150%" or "-6dB". Relative values (pitch, volume) need an explicit sign.
+fn parse_number(value: &str, unit: &str, signed: bool) -> Option<f64> {
+    let number = value.strip_suffix(unit)?;
+    let digits = if signed {
+        number.strip_prefix('+').or_else(|| number.strip_prefix('-'))?
+    } else {
+        number
+    };
+
+    if digits.is_empty() || !digits.chars().all(|character| character.is_ascii_digit() || character == '.') {
+        return None;
+    }
+
+    number.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use xmlparser::{ElementEnd, Token, Tokenizer};
+
+    // Pieces that could close a quote, a tag or a comment if they got through unescaped, plus some IPA
+    const HOSTILE: [&str; 17] = ["'", "\"", "<", ">", "&", "/", "=", " ", "{}", "'/><break/>", "&amp;", "]]>", "<!--", "-->", "ə", "ˈ", "ʃ"];
+    // Prosody values that pass validate(), so they get mixed in with the made up ones
+    const RATES: [&str; 3] = ["x-slow", "150%", "20%"];
+    const PITCHES: [&str; 3] = ["high", "+10%", "-5.5%"];
+    const VOLUMES: [&str; 3] = ["loud", "+6dB", "-3dB"];
+
+    fn hostile(rng: &mut StdRng) -> String {
+        (0..rng.gen_range(0..6)).map(|_| HOSTILE[rng.gen_range(0..HOSTILE.len())]).collect()
+    }
+
+    fn prosody_value(rng: &mut StdRng, valid: &[&str]) -> Option<String> {
+        match rng.gen_range(0..3) {
+            0 => None,
+            1 => Some(valid[rng.gen_range(0..valid.len())].to_string()),
+            _ => Some(hostile(rng)),
+        }
+    }
+
+    #[derive(Default)]
+    struct Parsed {
+        // Qualified names, in document order, without the <speak> wrapped around it
+        elements: Vec<String>,
+        // Unescaped values
+        attributes: Vec<(String, String)>,
+        text: String,
+    }
+
+    impl Parsed {
+        fn attribute(&self, name: &str) -> Vec<&str> {
+            self.attributes.iter().filter(|(attribute, _)| attribute == name).map(|(_, value)| value.as_str()).collect()
+        }
+    }
+
+    fn qualified(prefix: &str, local: &str) -> String {
+        match prefix {
+            "" => local.to_string(),
+            _ => format!("{prefix}:{local}"),
+        }
+    }
+
+    // xmlparser leaves entities alone
+    fn unescape(escaped: &str) -> String {
+        let mut text = String::new();
+        let mut rest = escaped;
+        while let Some(start) = rest.find('&') {
+            text.push_str(&rest[..start]);
+            let end = start + rest[start..].find(';').unwrap_or_else(|| panic!("bare & in {escaped:?}"));
+            text.push(match &rest[start + 1..end] {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "apos" => '\'',
+                "quot" => '"',
+                entity => panic!("unknown entity {entity:?} in {escaped:?}"),
+            });
+            rest = &rest[end + 1..];
+        }
+        text.push_str(rest);
+
+        text
+    }
+
+    // Polly gets it wrapped in <speak>, which is how it has to parse
+    fn parse(ssml: &str) -> Parsed {
+        let document = format!("<speak>{ssml}</speak>");
+        let mut parsed = Parsed::default();
+        let mut open = Vec::new();
+        for token in Tokenizer::from(document.as_str()) {
+            match token.unwrap_or_else(|error| panic!("{ssml:?} isn't XML: {error}")) {
+                Token::ElementStart { prefix, local, .. } => {
+                    let name = qualified(&prefix, &local);
+                    open.push(name.clone());
+                    parsed.elements.push(name);
+                }
+                Token::Attribute { prefix, local, value, .. } => parsed.attributes.push((qualified(&prefix, &local), unescape(&value))),
+                Token::ElementEnd { end: ElementEnd::Open, .. } => {}
+                Token::ElementEnd { end: ElementEnd::Empty, .. } => {
+                    open.pop();
+                }
+                Token::ElementEnd { end: ElementEnd::Close(prefix, local), .. } => {
+                    assert_eq!(open.pop(), Some(qualified(&prefix, &local)), "mismatched close in {ssml:?}");
+                }
+                Token::Text { text } => parsed.text.push_str(&unescape(&text)),
+                other => panic!("unexpected {other:?} in {ssml:?}"),
+            }
+        }
+        assert!(open.is_empty(), "unclosed {open:?} in {ssml:?}");
+        parsed.elements.remove(0);
+
+        parsed
+    }
+
+    fn params<'a>(words: &'a [&'a str], prosody: &'a Prosody<'a>) -> SsmlParams<'a> {
+        SsmlParams { alphabet: "ipa", words, break_ms: None, language_code: None, carrier: None, effect: None, prosody }
+    }
+
+    #[test]
+    fn no_prosody_is_the_bare_phoneme() {
+        assert_eq!(build_ssml(&params(&["həˈloʊ"], &Prosody::default())), "<phoneme alphabet='ipa' ph='həˈloʊ'></phoneme>");
+    }
+
+    #[test]
+    fn prosody_wraps_the_phonemes() {
+        let slow = Prosody { rate: Some("slow"), ..Default::default() };
+        assert_eq!(build_ssml(&params(&["həˈloʊ"], &slow)), "<prosody rate='slow'><phoneme alphabet='ipa' ph='həˈloʊ'></phoneme></prosody>");
+
+        let all = Prosody { rate: Some("80%"), pitch: Some("-10%"), volume: Some("+6dB") };
+        assert_eq!(build_ssml(&params(&["həˈloʊ"], &all)), "<prosody rate='80%' pitch='-10%' volume='+6dB'><phoneme alphabet='ipa' ph='həˈloʊ'></phoneme></prosody>");
+
+        let pitch_only = Prosody { pitch: Some("x-high"), ..Default::default() };
+        assert_eq!(pitch_only.wrap("content"), "<prosody pitch='x-high'>content</prosody>");
+    }
+
+    #[test]
+    fn breaks_go_between_words_and_are_clamped() {
+        let words = ["wʌn", "tuː"];
+        let prosody = Prosody::default();
+        assert_eq!(build_ssml(&SsmlParams { break_ms: Some(250), ..params(&words, &prosody) }), "<phoneme alphabet='ipa' ph='wʌn'></phoneme><break time='250ms'/><phoneme alphabet='ipa' ph='tuː'></phoneme>");
+        assert_eq!(build_ssml(&SsmlParams { break_ms: Some(60_000), ..params(&words, &prosody) }), format!("<phoneme alphabet='ipa' ph='wʌn'></phoneme><break time='{MAX_BREAK_MS}ms'/><phoneme alphabet='ipa' ph='tuː'></phoneme>"));
+        assert_eq!(build_ssml(&SsmlParams { break_ms: Some(0), ..params(&words, &prosody) }), "<phoneme alphabet='ipa' ph='wʌn'></phoneme><break time='0ms'/><phoneme alphabet='ipa' ph='tuː'></phoneme>");
+        // Without one the words are only spaced
+        assert_eq!(build_ssml(&params(&words, &prosody)), "<phoneme alphabet='ipa' ph='wʌn'></phoneme> <phoneme alphabet='ipa' ph='tuː'></phoneme>");
+        // Nothing to put it between
+        assert!(!build_ssml(&SsmlParams { break_ms: Some(250), ..params(&["wʌn"], &prosody) }).contains("<break"));
+    }
+
+    #[test]
+    fn language_code_goes_in_xml_lang() {
+        let prosody = Prosody::default();
+        assert_eq!(build_ssml(&SsmlParams { language_code: Some("en-GB"), ..params(&["həˈləʊ"], &prosody) }), "<lang xml:lang='en-GB'><phoneme alphabet='ipa' ph='həˈləʊ'></phoneme></lang>");
+        // Inside the prosody, around everything else
+        let slow = Prosody { rate: Some("slow"), ..Default::default() };
+        assert_eq!(
+            build_ssml(&SsmlParams { language_code: Some("fr-FR"), carrier: Some("Le mot est {}."), ..params(&["mo"], &slow) }),
+            "<prosody rate='slow'><lang xml:lang='fr-FR'>Le mot est <phoneme alphabet='ipa' ph='mo'></phoneme>.</lang></prosody>"
+        );
+    }
+
+    #[test]
+    fn carrier_sentence_goes_around_the_phonemes() {
+        let prosody = Prosody::default();
+        assert_eq!(
+            build_ssml(&SsmlParams { carrier: Some("The word is {}."), ..params(&["həˈloʊ"], &prosody) }),
+            "The word is <phoneme alphabet='ipa' ph='həˈloʊ'></phoneme>."
+        );
+        assert_eq!(
+            build_ssml(&SsmlParams { carrier: Some("{} & {} again"), break_ms: Some(100), ..params(&["wʌn", "tuː"], &prosody) }),
+            "<phoneme alphabet='ipa' ph='wʌn'></phoneme><break time='100ms'/><phoneme alphabet='ipa' ph='tuː'></phoneme> &amp; {} again"
+        );
+        // The carrier is text, never markup
+        assert_eq!(
+            build_ssml(&SsmlParams { carrier: Some("<break time='5s'/>{}"), ..params(&["ə"], &prosody) }),
+            "&lt;break time=&apos;5s&apos;/&gt;<phoneme alphabet='ipa' ph='ə'></phoneme>"
+        );
+    }
+
+    #[test]
+    fn effect_wraps_everything_inside_the_prosody() {
+        let prosody = Prosody::default();
+        assert_eq!(
+            build_ssml(&SsmlParams { effect: Some("whispered"), ..params(&["həˈloʊ"], &prosody) }),
+            "<amazon:effect name='whispered'><phoneme alphabet='ipa' ph='həˈloʊ'></phoneme></amazon:effect>"
+        );
+        let soft = Prosody { volume: Some("soft"), ..Default::default() };
+        assert_eq!(
+            build_ssml(&SsmlParams { effect: Some("whispered"), language_code: Some("en-US"), break_ms: Some(100), ..params(&["wʌn", "tuː"], &soft) }),
+            "<prosody volume='soft'><amazon:effect name='whispered'><lang xml:lang='en-US'><phoneme alphabet='ipa' ph='wʌn'></phoneme><break time='100ms'/><phoneme alphabet='ipa' ph='tuː'></phoneme></lang></amazon:effect></prosody>"
+        );
+        assert_eq!(
+            build_ssml(&SsmlParams { effect: Some("drc"), ..params(&["ə"], &prosody) }),
+            "<amazon:effect name='drc'><phoneme alphabet='ipa' ph='ə'></phoneme></amazon:effect>"
+        );
+    }
+
+    #[test]
+    fn validates_prosody_values() {
+        assert!(Prosody { rate: Some("x-fast"), pitch: Some("+5.5%"), volume: Some("-3dB") }.validate().is_ok());
+        assert_eq!(Prosody { rate: Some("10%"), ..Default::default() }.validate(), Err(("rate", "10%")));
+        assert_eq!(Prosody { rate: Some("201%"), ..Default::default() }.validate(), Err(("rate", "201%")));
+        assert_eq!(Prosody { rate: Some("+50%"), ..Default::default() }.validate(), Err(("rate", "+50%")));
+        assert_eq!(Prosody { pitch: Some("10%"), ..Default::default() }.validate(), Err(("pitch", "10%")));
+        assert_eq!(Prosody { pitch: Some("+%"), ..Default::default() }.validate(), Err(("pitch", "+%")));
+        assert_eq!(Prosody { volume: Some("+6%"), ..Default::default() }.validate(), Err(("volume", "+6%")));
+        assert_eq!(Prosody { volume: Some("'/>"), ..Default::default() }.validate(), Err(("volume", "'/>")));
+    }
+
+    #[test]
+    fn escapes_markup() {
+        assert_eq!(escape("'"), "&apos;");
+        assert_eq!(escape("\""), "&quot;");
+        assert_eq!(escape("<"), "&lt;");
+        assert_eq!(escape(">"), "&gt;");
+        assert_eq!(escape("&"), "&amp;");
+        assert_eq!(escape("&amp;"), "&amp;amp;");
+        assert_eq!(escape("'/><break/>"), "&apos;/&gt;&lt;break/&gt;");
+    }
+
+    #[test]
+    fn leaves_ipa_alone() {
+        assert_eq!(escape("həˈloʊ wɜːld"), "həˈloʊ wɜːld");
+    }
+
+    #[test]
+    fn hostile_input_stays_inside_its_attribute_or_text() {
+        for seed in 0..2000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let alphabet = hostile(&mut rng);
+            let words: Vec<String> = (0..rng.gen_range(1..4)).map(|_| hostile(&mut rng)).collect();
+            let words: Vec<&str> = words.iter().map(String::as_str).collect();
+            let break_ms = rng.gen_bool(0.5).then(|| rng.gen_range(0..5000));
+            let language_code = rng.gen_bool(0.5).then(|| hostile(&mut rng));
+            let carrier = rng.gen_bool(0.5).then(|| format!("{}{{}}{}", hostile(&mut rng), hostile(&mut rng)));
+            let effect = rng.gen_bool(0.5).then(|| hostile(&mut rng));
+            let (rate, pitch, volume) = (prosody_value(&mut rng, &RATES), prosody_value(&mut rng, &PITCHES), prosody_value(&mut rng, &VOLUMES));
+            let mut prosody = Prosody { rate: rate.as_deref(), pitch: pitch.as_deref(), volume: volume.as_deref() };
+            // The server turns these away before building anything
+            if prosody.validate().is_err() {
+                prosody = Prosody::default();
+            }
+
+            let ssml = build_ssml(&SsmlParams {
+                alphabet: &alphabet,
+                words: &words,
+                break_ms,
+                language_code: language_code.as_deref(),
+                carrier: carrier.as_deref(),
+                effect: effect.as_deref(),
+                prosody: &prosody,
+            });
+            let parsed = parse(&ssml);
+
+            let allowed = ["prosody", "amazon:effect", "lang", "phoneme", "break"];
+            assert!(parsed.elements.iter().all(|element| allowed.contains(&element.as_str())), "seed {seed}: {ssml:?}");
+            assert_eq!(parsed.elements.iter().filter(|element| *element == "phoneme").count(), words.len(), "seed {seed}: {ssml:?}");
+            assert_eq!(parsed.attribute("ph"), words, "seed {seed}: {ssml:?}");
+            assert!(parsed.attribute("alphabet").iter().all(|value| *value == alphabet), "seed {seed}: {ssml:?}");
+            assert_eq!(parsed.attribute("xml:lang"), language_code.as_deref().into_iter().collect::<Vec<_>>(), "seed {seed}: {ssml:?}");
+            assert_eq!(parsed.attribute("name"), effect.as_deref().into_iter().collect::<Vec<_>>(), "seed {seed}: {ssml:?}");
+            for (name, value) in [("rate", prosody.rate), ("pitch", prosody.pitch), ("volume", prosody.volume)] {
+                assert_eq!(parsed.attribute(name), value.into_iter().collect::<Vec<_>>(), "seed {seed}: {ssml:?}");
+            }
+
+            let (before, after) = carrier.as_deref().and_then(|carrier| carrier.split_once("{}")).unwrap_or_default();
+            let separators = match break_ms {
+                Some(_) => String::new(),
+                None => " ".repeat(words.len() - 1),
+            };
+            assert_eq!(parsed.text, format!("{before}{separators}{after}"), "seed {seed}: {ssml:?}");
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,139 @@
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use aws_sdk_polly::output::SynthesizeSpeechOutput;
+use aws_sdk_polly::types::SdkError;
+use aws_sdk_polly::Client;
+use bytes::Bytes;
+use rocket::http::Status;
+use rocket::tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+use crate::error::ApiError;
+use crate::{retry, synthesis_failed, SynthesisKey};
+
+// The retries have already waited a few hundred milliseconds by the time this is sent
+const POLLY_THROTTLED_RETRY_SECS: u64 = 1;
+
+pub type AudioStream = Pin<Box<dyn AsyncRead + Send>>;
+
+// Whatever turns a request into audio. Caching, timeouts and the concurrency limit are all
+// handled around it in Polly, so another provider would only need to do the synthesis itself.
+#[rocket::async_trait]
+pub trait SpeechSynthesizer: Send + Sync {
+    // Returns as soon as audio starts arriving
+    async fn stream(&self, key: &SynthesisKey) -> Result<AudioStream, ApiError>;
+
+    async fn synthesize(&self, key: &SynthesisKey) -> Result<Bytes, ApiError> {
+        let mut audio = Vec::new();
+        self.stream(key).await?.read_to_end(&mut audio).await.map_err(|err| synthesis_failed(&err))?;
+        Ok(Bytes::from(audio))
+    }
+}
+
+pub struct PollySynthesizer {
+    client: Client,
+}
+
+impl PollySynthesizer {
+    pub fn new(client: Client) -> Self {
+        PollySynthesizer { client }
+    }
+
+    async fn request_speech(&self, key: &SynthesisKey) -> Result<SynthesizeSpeechOutput, ApiError> {
+        let result = retry::with_retries(|| {
+            self.client
+                .synthesize_speech()
+                .output_format(key.output_format.clone())
+                .text(key.text.clone())
+                .text_type(key.text_type.clone())
+                .voice_id(key.voice.clone())
+                .engine(key.engine.clone())
+                .set_sample_rate(key.sample_rate.clone())
+                .set_speech_mark_types(Some(key.speech_marks.clone()).filter(|speech_marks| !speech_marks.is_empty()))
+                .set_lexicon_names(Some(key.lexicon_names.clone()).filter(|lexicon_names| !lexicon_names.is_empty()))
+                .set_language_code(key.language_code.clone())
+                .send()
+        }).await;
+
+        match result {
+            Ok(resp) => Ok(resp),
+            // Polly's only way of saying a generative voice can't do <phoneme>
+            Err(SdkError::ServiceError { err, .. }) if key.engine.as_str() == "generative" && (err.is_invalid_ssml_exception() || err.is_engine_not_supported_exception()) => {
+                Err(ApiError::bad_request("unsupported_phonemes", format!("Voice {} can't synthesize phonemes on the generative engine. Use standard or neural instead", key.voice.as_str())))
+            }
+            // Whatever the voice doesn't like about the effect or prosody, we already escaped everything
+            Err(SdkError::ServiceError { err, .. }) if err.is_invalid_ssml_exception() => {
+                Err(ApiError::bad_request("unsupported_ssml", format!("Voice {} on the {} engine can't do the effect or prosody asked for", key.voice.as_str(), key.engine.as_str())))
+            }
+            // Lexicons live in the AWS account, so we only find out here
+            Err(SdkError::ServiceError { err, .. }) if err.is_lexicon_not_found_exception() => {
+                Err(ApiError::bad_request("unknown_lexicon", format!("One of the lexicons {} doesn't exist in this region", key.lexicon_names.join(", "))))
+            }
+            // Out of retries, but it's worth the client trying again in a bit
+            Err(err) if retry::is_throttled(&err) => {
+                warn!("Polly is still throttling after {} attempts: {err}", retry::MAX_ATTEMPTS);
+                Err(ApiError::new(Status::ServiceUnavailable, "polly_throttled", "Polly is throttling requests, try again shortly".to_string()).with_retry_after(POLLY_THROTTLED_RETRY_SECS))
+            }
+            Err(err) => Err(synthesis_failed(&err)),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl SpeechSynthesizer for PollySynthesizer {
+    async fn stream(&self, key: &SynthesisKey) -> Result<AudioStream, ApiError> {
+        let resp = self.request_speech(key).await?;
+        Ok(Box::pin(resp.audio_stream.into_async_read()))
+    }
+
+    async fn synthesize(&self, key: &SynthesisKey) -> Result<Bytes, ApiError> {
+        let resp = self.request_speech(key).await?;
+        Ok(resp.audio_stream.collect().await.map_err(|err| synthesis_failed(&err))?.into_bytes())
+    }
+}
+
+// Fails once more than the limit has been read, so a runaway clip can't eat all the memory
+pub struct LimitedReader {
+    inner: AudioStream,
+    remaining: usize,
+}
+
+impl LimitedReader {
+    pub fn new(inner: AudioStream, limit: usize) -> Self {
+        LimitedReader { inner, remaining: limit }
+    }
+}
+
+impl AsyncRead for LimitedReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        match self.inner.as_mut().poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                let read = buf.filled().len() - before;
+                if read > self.remaining {
+                    return Poll::Ready(Err(io::Error::other(AudioTooLarge)));
+                }
+                self.remaining -= read;
+                Poll::Ready(Ok(()))
+            }
+            other => other,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AudioTooLarge;
+
+impl fmt::Display for AudioTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "audio is over the size limit")
+    }
+}
+
+impl std::error::Error for AudioTooLarge {}
+
+pub fn is_too_large(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<AudioTooLarge>())
+}
\ No newline at end of file
@@ -0,0 +1,31 @@
+use tracing_subscriber::layer::SubscriberExt;
+
+// Off unless OTEL_EXPORTER_OTLP_ENDPOINT is set. The exporter reads that and the rest of the
+// standard OTEL_* variables (OTEL_SERVICE_NAME and so on) itself.
+pub fn init() {
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() {
+        return;
+    }
+
+    let tracer = match opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .install_batch(opentelemetry::runtime::Tokio)
+    {
+        Ok(tracer) => tracer,
+        Err(err) => {
+            error!("Failed to set up trace export, carrying on without it: {err}");
+            return;
+        }
+    };
+
+    let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    if let Err(err) = tracing::subscriber::set_global_default(subscriber) {
+        error!("Failed to set up trace export, carrying on without it: {err}");
+    }
+}
+
+// Flushes whatever spans are still batched up
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
\ No newline at end of file
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+// How a voice gets picked when the request doesn't name one
+#[derive(Clone, Copy, PartialEq)]
+pub enum Strategy {
+    Random,
+    // Takes turns through a language's voices, so they all get used about as much
+    RoundRobin,
+    // Alphabetically, so the same request always gets the same voice
+    First,
+}
+
+pub const STRATEGY_NAMES: [&str; 3] = ["random", "round-robin", "first"];
+
+impl FromStr for Strategy {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, ()> {
+        match name {
+            "random" => Ok(Strategy::Random),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "first" => Ok(Strategy::First),
+            _ => Err(()),
+        }
+    }
+}
+
+// Per language rather than per candidate list, gender and engine filters move through the same turns
+#[derive(Default)]
+pub struct RoundRobin {
+    turns: Mutex<HashMap<String, usize>>,
+}
+
+impl RoundRobin {
+    pub fn next(&self, language_name: &str, candidates: usize) -> usize {
+        let mut turns = self.turns.lock().unwrap();
+        let turn = turns.entry(language_name.to_string()).or_insert(0);
+        let index = *turn % candidates.max(1);
+        *turn = turn.wrapping_add(1);
+
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_strategy_name() {
+        assert!(STRATEGY_NAMES.iter().all(|name| name.parse::<Strategy>().is_ok()));
+        assert!("Random".parse::<Strategy>().is_err());
+        assert!("round_robin".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn round_robin_takes_turns_per_language() {
+        let round_robin = RoundRobin::default();
+        assert_eq!((0..5).map(|_| round_robin.next("English", 3)).collect::<Vec<_>>(), [0, 1, 2, 0, 1]);
+        assert_eq!(round_robin.next("French", 3), 0);
+        // Fewer candidates after filtering still lands in range
+        assert_eq!(round_robin.next("English", 2), 1);
+        assert_eq!(round_robin.next("English", 0), 0);
+    }
+}
\ No newline at end of file
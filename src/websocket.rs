@@ -0,0 +1,57 @@
+use std::net::IpAddr;
+
+use futures::{SinkExt, StreamExt};
+use rocket::serde::json::{self, json};
+use rocket::State;
+use rocket_ws::{Channel, Message, WebSocket};
+use rocket_validation::Validate;
+
+use crate::auth::ApiKey;
+use crate::config::Settings;
+use crate::error::ApiError;
+use crate::ip_filter::NotDenied;
+use crate::language_map::{LanguageMap, Languages};
+use crate::metrics::Metrics;
+use crate::rate_limit::{self, RateLimits};
+use crate::{synthesize, IfNoneMatch, Polly, RequestData};
+
+// Each text message is a request like POST / takes, answered with the audio as a binary message
+// or {"error": ...} as a text one. They're handled one at a time, so answers come back in order.
+// Every message counts against the rate limit, the connection itself doesn't.
+#[get("/speak/ws")]
+#[allow(clippy::too_many_arguments)]
+pub fn speak_ws<'r>(ws: WebSocket, polly: &'r State<Polly>, languages: Languages, settings: &'r State<Settings>, metrics: &'r State<Metrics>, rate_limits: &'r State<RateLimits>, ip: Option<IpAddr>, _not_denied: NotDenied, api_key: ApiKey) -> Channel<'r> {
+    // None for callers the rate limit doesn't apply to
+    let client = (!rate_limits.exempts(ip)).then(|| rate_limit::client_id(Some(&api_key), ip));
+
+    ws.channel(move |mut stream| Box::pin(async move {
+        while let Some(message) = stream.next().await {
+            let text = match message? {
+                Message::Text(text) => text,
+                Message::Close(_) => break,
+                // Pings are answered for us, and binary means nothing here
+                _ => continue,
+            };
+
+            let reply = match synthesize_message(&text, client.as_deref(), polly, &languages, settings, metrics, rate_limits).await {
+                Ok(audio) => Message::Binary(audio),
+                Err(error) => Message::Text(json::to_string(&json!({ "error": error })).unwrap_or_default()),
+            };
+            stream.send(reply).await?;
+        }
+
+        Ok(())
+    }))
+}
+
+async fn synthesize_message(text: &str, client: Option<&str>, polly: &Polly, languages: &LanguageMap, settings: &Settings, metrics: &Metrics, rate_limits: &RateLimits) -> Result<Vec<u8>, ApiError> {
+    if let Some(Err(retry_after_secs)) = client.map(|client| rate_limits.check(client.to_string())) {
+        return Err(ApiError::new(rocket::http::Status::TooManyRequests, "rate_limited", format!("Rate limit exceeded, try again in {retry_after_secs}s")).with_retry_after(retry_after_secs));
+    }
+
+    let data: RequestData = json::from_str(text).map_err(|err| ApiError::bad_request("invalid_request", err.to_string()))?;
+    data.validate().map_err(|errors| ApiError::from_validation_errors(&errors))?;
+
+    let speech = synthesize(&data, &IfNoneMatch(None), polly, languages, settings, metrics).await?;
+    Ok(speech.audio.unwrap_or_default().to_vec())
+}
\ No newline at end of file